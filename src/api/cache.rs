@@ -0,0 +1,82 @@
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{
+    api::load::ModelQuery,
+    cache::{CacheRequest, CacheResponse},
+    error::ApiError,
+    ThreadRequest, ThreadState,
+};
+
+async fn request_cache(
+    sender: flume::Sender<ThreadRequest>,
+    model: String,
+    request: CacheRequest,
+) -> Result<Json<CacheResponse>, ApiError> {
+    let (cache_sender, cache_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Cache {
+        model,
+        request,
+        sender: cache_sender,
+    });
+    match cache_receiver.recv_async().await {
+        Ok(response) => Ok(Json(response)),
+        Err(_) => Err(ApiError::ModelNotLoaded),
+    }
+}
+
+/// `/api/cache`.
+pub async fn list(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<CacheResponse>, ApiError> {
+    request_cache(sender, query.model, CacheRequest::List).await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheIdRequest {
+    ids: Vec<usize>,
+}
+
+/// `/api/cache/evict`.
+pub async fn evict(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+    Json(request): Json<CacheIdRequest>,
+) -> Result<Json<CacheResponse>, ApiError> {
+    request_cache(sender, query.model, CacheRequest::Evict(request.ids)).await
+}
+
+/// `/api/cache/flush`.
+pub async fn flush(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<CacheResponse>, ApiError> {
+    request_cache(sender, query.model, CacheRequest::Flush).await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PinRequest {
+    prompts: Vec<String>,
+}
+
+/// `/api/cache/pin`.
+pub async fn pin(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+    Json(request): Json<PinRequest>,
+) -> Result<Json<CacheResponse>, ApiError> {
+    request_cache(sender, query.model, CacheRequest::Pin(request.prompts)).await
+}
+
+/// `/api/cache/unpin`.
+pub async fn unpin(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+    Json(request): Json<CacheIdRequest>,
+) -> Result<Json<CacheResponse>, ApiError> {
+    request_cache(sender, query.model, CacheRequest::Unpin(request.ids)).await
+}
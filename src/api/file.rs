@@ -5,13 +5,13 @@ use std::{
 };
 
 use anyhow::{bail, Result};
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, http::StatusCode, Json};
 use itertools::Itertools;
 use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::{config::Config, ThreadState};
+use crate::{config::Config, error::ApiError, ThreadState};
 
 const PERMITTED_PATHS: [&str; 4] = [
     "assets/models",
@@ -33,7 +33,8 @@ fn check_path_permitted(path: impl AsRef<Path>, permitted: &[&str]) -> Result<()
     bail!("path not valid");
 }
 
-fn check_path(path: impl AsRef<Path>) -> Result<()> {
+/// Check that the path is under one of the directories the API may access.
+pub fn check_path(path: impl AsRef<Path>) -> Result<()> {
     check_path_permitted(path, &PERMITTED_PATHS)
 }
 
@@ -79,10 +80,10 @@ pub struct FileInfo {
 pub async fn dir(
     State(ThreadState(_)): State<ThreadState>,
     Json(request): Json<FileInfoRequest>,
-) -> impl IntoResponse {
+) -> Result<Json<Vec<FileInfo>>, ApiError> {
     if let Err(err) = check_path(&request.path) {
-        log::error!("check path failed: {}", err);
-        return Err(StatusCode::FORBIDDEN);
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
     }
     match std::fs::read_dir(request.path) {
         Ok(dir) => {
@@ -107,17 +108,19 @@ pub async fn dir(
                     })
                 })
                 .collect_vec();
-            Ok((StatusCode::OK, Json(files)))
+            Ok(Json(files))
         }
         Err(err) => {
-            log::error!("failed to read directory: {}", err);
-            Err(StatusCode::NOT_FOUND)
+            tracing::error!("failed to read directory: {}", err);
+            Err(ApiError::NotFound(format!(
+                "failed to read directory: {err}"
+            )))
         }
     }
 }
 
 /// `/api/models/list`.
-pub async fn models(state: State<ThreadState>) -> impl IntoResponse {
+pub async fn models(state: State<ThreadState>) -> Result<Json<Vec<FileInfo>>, ApiError> {
     let request = FileInfoRequest {
         path: "assets/models".into(),
         is_sha: true,
@@ -137,14 +140,14 @@ pub struct UnzipRequest {
 pub async fn unzip(
     State(ThreadState(_)): State<ThreadState>,
     Json(request): Json<UnzipRequest>,
-) -> StatusCode {
+) -> Result<StatusCode, ApiError> {
     if let Err(err) = check_path(&request.path) {
-        log::error!("check path failed: {}", err);
-        return StatusCode::FORBIDDEN;
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
     }
     if let Err(err) = check_path_permitted(&request.output, &UNZIP_PATHS) {
-        log::error!("check path failed: {}", err);
-        return StatusCode::FORBIDDEN;
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
     }
 
     let unzip = move || -> Result<()> {
@@ -161,10 +164,10 @@ pub async fn unzip(
     };
 
     match unzip() {
-        Ok(_) => StatusCode::OK,
+        Ok(_) => Ok(StatusCode::OK),
         Err(err) => {
-            log::error!("failed to unzip: {}", err);
-            StatusCode::NOT_FOUND
+            tracing::error!("failed to unzip: {}", err);
+            Err(ApiError::NotFound(format!("failed to unzip: {err}")))
         }
     }
 }
@@ -178,16 +181,16 @@ pub struct LoadRequest {
 pub async fn load_config(
     State(ThreadState(_)): State<ThreadState>,
     Json(request): Json<LoadRequest>,
-) -> impl IntoResponse {
+) -> Result<Json<Config>, ApiError> {
     if let Err(err) = check_path(&request.path) {
-        log::error!("check path failed: {}", err);
-        return Err(StatusCode::FORBIDDEN);
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
     }
     match crate::load_config(request.path) {
-        Ok(config) => Ok((StatusCode::OK, Json(config))),
+        Ok(config) => Ok(Json(config)),
         Err(err) => {
-            log::error!("failed to load config: {}", err);
-            Err(StatusCode::NOT_FOUND)
+            tracing::error!("failed to load config: {}", err);
+            Err(ApiError::NotFound(format!("failed to load config: {err}")))
         }
     }
 }
@@ -202,10 +205,10 @@ pub struct SaveRequest {
 pub async fn save_config(
     State(ThreadState(_)): State<ThreadState>,
     Json(request): Json<SaveRequest>,
-) -> StatusCode {
+) -> Result<StatusCode, ApiError> {
     if let Err(err) = check_path(&request.path) {
-        log::error!("check path failed: {}", err);
-        return StatusCode::FORBIDDEN;
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
     }
 
     let write = || -> Result<()> {
@@ -217,18 +220,21 @@ pub async fn save_config(
 
     match request.path.extension() {
         Some(ext) if ext == "toml" => match write() {
-            Ok(_) => StatusCode::OK,
+            Ok(_) => Ok(StatusCode::OK),
             Err(err) => {
-                log::error!("failed to save config: {err}");
-                StatusCode::INTERNAL_SERVER_ERROR
+                tracing::error!("failed to save config: {err}");
+                Err(ApiError::Internal(format!("failed to save config: {err}")))
             }
         },
         _ => {
-            log::error!(
+            tracing::error!(
                 "failed to save config: file path {} is not toml",
                 request.path.to_string_lossy()
             );
-            StatusCode::FORBIDDEN
+            Err(ApiError::Forbidden(format!(
+                "file path {} is not toml",
+                request.path.to_string_lossy()
+            )))
         }
     }
 }
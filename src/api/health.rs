@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    api::load::ModelQuery, utils::try_request_info, ModelPulse, ThreadRequest, ThreadState,
+};
+
+/// How long the probes wait for the models to respond before failing.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadyResponse {
+    ready: bool,
+    /// ID of the model serving the query; empty if it is not loaded.
+    model: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveResponse {
+    live: bool,
+    models: Vec<ModelPulse>,
+}
+
+/// `/health`.
+pub async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// `/ready`, which succeeds if the model is loaded, i.e., it has passed a test forward pass.
+pub async fn ready(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> (StatusCode, Json<ReadyResponse>) {
+    match tokio::time::timeout(PROBE_TIMEOUT, try_request_info(sender, &query.model)).await {
+        Ok(Ok(info)) => {
+            let response = ReadyResponse {
+                ready: true,
+                model: info.reload.model_id(),
+            };
+            (StatusCode::OK, Json(response))
+        }
+        _ => {
+            let response = ReadyResponse {
+                ready: false,
+                model: Default::default(),
+            };
+            (StatusCode::SERVICE_UNAVAILABLE, Json(response))
+        }
+    }
+}
+
+/// `/live`, which fails if the inference loop of any model is stuck on its requests.
+pub async fn live(
+    State(ThreadState(sender)): State<ThreadState>,
+) -> (StatusCode, Json<LiveResponse>) {
+    let (pulse_sender, pulse_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Pulse(pulse_sender));
+
+    // the dispatcher or a worker is hung if not all of them reply in time
+    let mut models = vec![];
+    let collect = async {
+        while let Ok(pulse) = pulse_receiver.recv_async().await {
+            models.push(pulse);
+        }
+    };
+    let replied = tokio::time::timeout(PROBE_TIMEOUT, collect).await.is_ok();
+
+    let live = replied && models.iter().all(|pulse| pulse.live);
+    let status = match live {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(LiveResponse { live, models }))
+}
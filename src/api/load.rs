@@ -2,45 +2,86 @@ use std::time::Duration;
 
 use anyhow::Result;
 use axum::{
-    extract::State,
+    extract::{Query, State},
     http::StatusCode,
     response::{sse::Event, Sse},
-    Json,
+    Extension, Json,
 };
 use futures_util::{Stream, StreamExt};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use web_rwkv::model::ModelInfo;
 
 use crate::{
-    utils::{request_info, request_info_stream, try_request_info},
-    ReloadRequest, RuntimeInfo, ThreadRequest, ThreadState,
+    api::file::check_path,
+    cache::CacheStats,
+    error::ApiError,
+    memory::{self, MemoryFit},
+    shutdown::Closing,
+    utils::{request_info_stream, try_request_info},
+    LoadPhase, LoraVariant, ReloadRequest, RuntimeInfo, ThreadRequest, ThreadState,
 };
 
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ModelQuery {
+    /// Name of the model; empty for the default model.
+    pub model: String,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct InfoResponse {
     reload: ReloadRequest,
     model: ModelInfo,
+    cache: CacheStats,
 }
 
 /// `/api/models/info`.
-pub async fn info(State(ThreadState(sender)): State<ThreadState>) -> Json<InfoResponse> {
-    let RuntimeInfo { reload, model, .. } = request_info(sender, Duration::from_millis(500)).await;
-    Json(InfoResponse { reload, model })
+pub async fn info(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<InfoResponse>, ApiError> {
+    let RuntimeInfo {
+        reload,
+        model,
+        cache,
+        ..
+    } = try_request_info(sender, &query.model)
+        .await
+        .map_err(|_| ApiError::ModelNotLoaded)?;
+    Ok(Json(InfoResponse {
+        reload,
+        model,
+        cache,
+    }))
 }
 
-/// `/api/models/state`.
+/// `/api/models/state`, which ends when the server shuts down.
 pub async fn state(
     State(ThreadState(sender)): State<ThreadState>,
+    Extension(closing): Extension<Closing>,
+    Query(query): Query<ModelQuery>,
 ) -> Sse<impl Stream<Item = Result<Event>>> {
     let (info_sender, info_receiver) = flume::unbounded();
-    let task = request_info_stream(sender, info_sender, Duration::from_millis(500));
+    let task = request_info_stream(sender, query.model, info_sender, Duration::from_millis(500));
     tokio::task::spawn(task);
 
-    let stream = info_receiver.into_stream().map(|info| {
-        let RuntimeInfo { reload, model, .. } = info;
-        let json = serde_json::to_string(&InfoResponse { reload, model })?;
-        Ok(Event::default().data(json))
-    });
+    let stream = info_receiver
+        .into_stream()
+        .take_until(closing.wait())
+        .map(|info| {
+            let RuntimeInfo {
+                reload,
+                model,
+                cache,
+                ..
+            } = info;
+            let json = serde_json::to_string(&InfoResponse {
+                reload,
+                model,
+                cache,
+            })?;
+            Ok(Event::default().data(json))
+        });
     Sse::new(stream)
 }
 
@@ -48,21 +89,124 @@ pub async fn state(
 pub async fn load(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<ReloadRequest>,
-) -> StatusCode {
-    let (result_sender, result_receiver) = flume::unbounded();
+) -> Result<StatusCode, ApiError> {
+    let (progress_sender, progress_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Reload {
         request,
-        sender: Some(result_sender),
+        memory: None,
+        sender: Some(progress_sender),
     });
-    match result_receiver.recv_async().await.unwrap() {
-        true => StatusCode::OK,
-        false => StatusCode::INTERNAL_SERVER_ERROR,
+    while let Ok(progress) = progress_receiver.recv_async().await {
+        match progress.phase {
+            LoadPhase::Done => return Ok(StatusCode::OK),
+            LoadPhase::Failed => {
+                let error = progress.error.unwrap_or_default();
+                return Err(ApiError::Internal(format!(
+                    "failed to load the model: {error}"
+                )));
+            }
+            _ => {}
+        }
     }
+    Err(ApiError::Internal("failed to load the model".into()))
+}
+
+/// `/api/models/load/stream`.
+pub async fn load_stream(
+    State(ThreadState(sender)): State<ThreadState>,
+    Json(request): Json<ReloadRequest>,
+) -> Sse<impl Stream<Item = Result<Event>>> {
+    let (progress_sender, progress_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Reload {
+        request,
+        memory: None,
+        sender: Some(progress_sender),
+    });
+
+    let stream = progress_receiver.into_stream().map(|progress| {
+        let json = serde_json::to_string(&progress)?;
+        Ok(Event::default().data(json))
+    });
+    Sse::new(stream)
 }
 
 /// `/api/models/unload`.
-pub async fn unload(State(ThreadState(sender)): State<ThreadState>) -> StatusCode {
-    let _ = sender.send(ThreadRequest::Unload);
-    while try_request_info(sender.clone()).await.is_ok() {}
-    StatusCode::OK
+/// The model is not loaded on demand afterwards, until it is loaded through `/api/models/load`.
+pub async fn unload(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> Result<StatusCode, ApiError> {
+    let (unload_sender, unload_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Unload {
+        model: query.model.clone(),
+        sender: unload_sender,
+    });
+    match unload_receiver.recv_async().await {
+        Ok(()) => Ok(StatusCode::OK),
+        Err(_) => Err(ApiError::NotFound(format!(
+            "no model named {}",
+            query.model
+        ))),
+    }
+}
+
+/// `/api/models/lora`.
+/// Each variant takes the memory of a full copy of its base model.
+pub async fn lora(
+    State(ThreadState(sender)): State<ThreadState>,
+    Json(variant): Json<LoraVariant>,
+) -> Result<StatusCode, ApiError> {
+    if variant.name.is_empty() {
+        return Err(ApiError::BadRequest("the variant must be named".into()));
+    }
+    for lora in &variant.lora {
+        if let Err(err) = check_path(&lora.path) {
+            tracing::error!("check path failed: {}", err);
+            return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
+        }
+    }
+    if let Some(lora) = variant.lora.iter().find(|lora| !lora.path.is_file()) {
+        return Err(ApiError::NotFound(format!(
+            "no LoRA file at {}",
+            lora.path.to_string_lossy()
+        )));
+    }
+
+    let model = variant.model.clone();
+    let (lora_sender, lora_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Lora {
+        variant,
+        sender: lora_sender,
+    });
+    match lora_receiver.recv_async().await {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(ApiError::NotFound(format!("no model named {model}"))),
+        Err(_) => Err(ApiError::Internal("failed to register the variant".into())),
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct EstimateRequest {
+    #[serde(flatten)]
+    request: ReloadRequest,
+    /// Available GPU memory in MiB; the total is not checked if `0`.
+    memory: usize,
+}
+
+/// `/api/models/estimate`.
+pub async fn estimate(
+    State(ThreadState(_)): State<ThreadState>,
+    Json(EstimateRequest { request, memory }): Json<EstimateRequest>,
+) -> Result<Json<MemoryFit>, ApiError> {
+    if let Err(err) = check_path(&request.model_path) {
+        tracing::error!("check path failed: {}", err);
+        return Err(ApiError::Forbidden(format!("path not permitted: {err}")));
+    }
+    let estimate = memory::estimate(&request)
+        .map_err(|err| ApiError::BadRequest(format!("failed to read model info: {err}")))?;
+    let adapter = crate::select_adapter(request.adapter)
+        .await
+        .map_err(|err| ApiError::Internal(format!("failed to select adapter: {err}")))?;
+    Ok(Json(memory::check(estimate, &adapter, memory << 20)))
 }
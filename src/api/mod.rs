@@ -1,7 +1,16 @@
 pub mod adapter;
+pub mod cache;
 pub mod file;
+pub mod health;
 pub mod load;
+pub mod request;
+pub mod slots;
+pub mod usage;
 
 pub use adapter::adapters;
 pub use file::{dir, load_config, models, save_config, unzip};
-pub use load::{info, load, state, unload};
+pub use health::{health, live, ready};
+pub use load::{estimate, info, load, load_stream, lora, state, unload};
+pub use request::cancel;
+pub use slots::{slots, slots_stream};
+pub use usage::usage;
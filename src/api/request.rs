@@ -0,0 +1,24 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+
+use crate::{error::ApiError, ThreadRequest, ThreadState};
+
+/// `/api/requests/:id`.
+pub async fn cancel(
+    State(ThreadState(sender)): State<ThreadState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let (cancel_sender, cancel_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Cancel {
+        id: id.clone(),
+        sender: cancel_sender,
+    });
+    match cancel_receiver.recv_async().await {
+        Ok(true) => Ok(StatusCode::OK),
+        _ => Err(ApiError::NotFound(format!(
+            "no running request with id {id}"
+        ))),
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    response::{sse::Event, Sse},
+    Extension, Json,
+};
+use futures_util::{Stream, StreamExt};
+
+use crate::{
+    api::load::ModelQuery, error::ApiError, run::SlotsInfo, shutdown::Closing, ThreadRequest,
+    ThreadState,
+};
+
+async fn request_slots(
+    sender: flume::Sender<ThreadRequest>,
+    model: String,
+) -> Result<SlotsInfo, ApiError> {
+    let (slots_sender, slots_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Slots {
+        model,
+        sender: slots_sender,
+    });
+    slots_receiver
+        .recv_async()
+        .await
+        .map_err(|_| ApiError::ModelNotLoaded)
+}
+
+/// `/api/slots`.
+pub async fn slots(
+    State(ThreadState(sender)): State<ThreadState>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<SlotsInfo>, ApiError> {
+    request_slots(sender, query.model).await.map(Json)
+}
+
+/// `/api/slots/stream`, which ends when the server shuts down.
+pub async fn slots_stream(
+    State(ThreadState(sender)): State<ThreadState>,
+    Extension(closing): Extension<Closing>,
+    Query(query): Query<ModelQuery>,
+) -> Sse<impl Stream<Item = Result<Event>>> {
+    let (slots_sender, slots_receiver) = flume::unbounded();
+    let task = async move {
+        // stop once the client is gone, whether the model is loaded or not
+        while !slots_sender.is_disconnected() {
+            if let Ok(slots) = request_slots(sender.clone(), query.model.clone()).await {
+                if slots_sender.send(slots).is_err() {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    };
+    tokio::task::spawn(task);
+
+    let stream = slots_receiver
+        .into_stream()
+        .take_until(closing.wait())
+        .map(|slots| {
+            let json = serde_json::to_string(&slots)?;
+            Ok(Event::default().data(json))
+        });
+    Sse::new(stream)
+}
@@ -0,0 +1,11 @@
+use axum::{extract::Query, Extension, Json};
+
+use crate::usage::{UsageLog, UsageQuery, UsageSummary};
+
+/// `/api/usage`.
+pub async fn usage(
+    Extension(log): Extension<UsageLog>,
+    Query(query): Query<UsageQuery>,
+) -> Json<Vec<UsageSummary>> {
+    Json(log.summarize(query).await)
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    config::{ApiKey, Scope},
+    error::ApiError,
+    limit::digest,
+};
+
+/// Identity of the client whose key authorized the request: the name of the key, or else a digest of the key.
+#[derive(Debug, Clone)]
+pub struct Client(pub String);
+
+/// Keys by their tokens, and the scope required by the routes guarded.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    keys: Arc<HashMap<String, ApiKey>>,
+    scope: Scope,
+}
+
+impl Auth {
+    pub fn new(keys: Arc<HashMap<String, ApiKey>>, scope: Scope) -> Self {
+        Self { keys, scope }
+    }
+}
+
+/// Reject requests without a bearer token of a key having the scope; all requests pass if no key is configured.
+pub async fn authorize(
+    State(Auth { keys, scope }): State<Auth>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if keys.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+        .ok_or(ApiError::Unauthorized(
+            "no API key provided in the Authorization header".into(),
+        ))?;
+    let key = keys
+        .get(token)
+        .ok_or(ApiError::Unauthorized("invalid API key provided".into()))?;
+    if !key.scopes.contains(&scope) {
+        let scope = match scope {
+            Scope::Inference => "inference",
+            Scope::Model => "model",
+            Scope::File => "file",
+        };
+        return Err(ApiError::Forbidden(format!(
+            "the API key does not have the {scope} scope"
+        )));
+    }
+
+    let client = match key.name.is_empty() {
+        true => format!("key-{}", &digest(&key.key)[..16]),
+        false => key.name.clone(),
+    };
+    request.extensions_mut().insert(Client(client));
+    request.extensions_mut().insert(key.clone());
+    Ok(next.run(request).await)
+}
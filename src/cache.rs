@@ -0,0 +1,428 @@
+use std::{collections::HashSet, time::Instant};
+
+use itertools::Itertools;
+use qp_trie::Trie;
+use serde::Serialize;
+use web_rwkv::model::{ModelInfo, ModelVersion};
+
+use crate::run::{AsTokenSlice, Tokens};
+
+/// Number of tokens decoded as the preview of a cached prefix.
+const PREVIEW_TOKENS: usize = 32;
+
+/// Estimated size in bytes of one backed state of a single batch.
+pub fn state_size(info: &ModelInfo) -> usize {
+    let size = match info.version {
+        ModelVersion::V4 => info.num_layer * info.num_emb * 5,
+        ModelVersion::V5 | ModelVersion::V6 => {
+            let head_size = info.num_emb / info.num_head.max(1);
+            info.num_layer * info.num_emb * (head_size + 2)
+        }
+    };
+    size * std::mem::size_of::<f32>()
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheItem<B> {
+    /// Unique id of the item, used for management.
+    pub id: usize,
+    /// The backed state at the end of the prefix.
+    pub state: B,
+    /// When the item is first inserted.
+    pub created: Instant,
+    /// When the item is last inserted or checked out.
+    pub accessed: Instant,
+    /// Number of times the item is checked out.
+    pub hits: usize,
+}
+
+impl<B> CacheItem<B> {
+    fn new(id: usize, state: B) -> Self {
+        let now = Instant::now();
+        Self {
+            id,
+            state,
+            created: now,
+            accessed: now,
+            hits: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    /// Number of checkouts that found a cached prefix.
+    pub hits: usize,
+    /// Number of checkouts that found nothing.
+    pub misses: usize,
+    /// Number of items evicted due to the limits.
+    pub evictions: usize,
+    /// Number of items currently cached.
+    pub items: usize,
+    /// Estimated memory used by the cache in bytes.
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheEntry {
+    pub id: usize,
+    /// Number of tokens of the prefix.
+    pub len: usize,
+    /// Seconds since the item is inserted.
+    pub age: u64,
+    /// Number of times the item is checked out.
+    pub hits: usize,
+    /// Pinned items are never evicted.
+    pub pinned: bool,
+    /// Decoded text at the start of the prefix.
+    pub preview: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum CacheRequest {
+    /// List all cached items.
+    List,
+    /// Remove items of the given ids, pinned or not.
+    Evict(Vec<usize>),
+    /// Remove all items that are not pinned.
+    Flush,
+    /// Compute the states of the prompts and pin them.
+    Pin(Vec<String>),
+    /// Unpin items of the given ids.
+    Unpin(Vec<usize>),
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheResponse {
+    pub stats: CacheStats,
+    pub items: Vec<CacheEntry>,
+}
+
+/// A prefix cache of backed states, bounded in both item count and memory.
+/// Least recently used items are evicted first.
+#[derive(Debug)]
+pub struct BackedCache<B> {
+    trie: Trie<Tokens, CacheItem<B>>,
+    pinned: HashSet<Vec<u16>>,
+    next_id: usize,
+    item_size: usize,
+    max_items: usize,
+    max_size: usize,
+    hits: usize,
+    misses: usize,
+    evictions: usize,
+}
+
+impl<B: Clone> BackedCache<B> {
+    /// Create a cache holding at most `max_items` states of `item_size` bytes each, and at most `max_size` bytes in total.
+    pub fn new(item_size: usize, max_items: usize, max_size: usize) -> Self {
+        Self {
+            trie: Trie::new(),
+            pinned: HashSet::new(),
+            next_id: 0,
+            item_size,
+            max_items,
+            max_size,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            items: self.trie.count(),
+            size: self.trie.count() * self.item_size,
+        }
+    }
+
+    /// Search for the longest cached prefix of `tokens`.
+    /// Returns the prefix together with a copy of its state, or `None` on a cache miss.
+    pub fn checkout(&mut self, tokens: &[u16]) -> Option<(Vec<u16>, B)> {
+        let prefix = self.trie.longest_common_prefix(tokens.as_token_slice());
+        let len = (1..=prefix.len())
+            .rev()
+            .find(|len| self.trie.contains_key(prefix[0..*len].as_token_slice()))
+            .unwrap_or_default();
+        let prefix = prefix[0..len].to_vec();
+
+        match self.trie.get_mut(prefix[..].as_token_slice()) {
+            Some(item) if len > 0 => {
+                item.accessed = Instant::now();
+                item.hits += 1;
+                self.hits += 1;
+                Some((prefix, item.state.clone()))
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert or refresh the state of `tokens`, evicting old items if the limits are exceeded.
+    pub fn insert(&mut self, tokens: Tokens, state: B) {
+        match self.trie.get_mut(&tokens) {
+            Some(item) => {
+                item.state = state;
+                item.accessed = Instant::now();
+            }
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.trie.insert(tokens, CacheItem::new(id, state));
+            }
+        }
+        self.evict();
+    }
+
+    /// List all items, with `preview` decoding the tokens of each prefix.
+    pub fn entries(&self, preview: impl Fn(&[u16]) -> String) -> Vec<CacheEntry> {
+        self.trie
+            .iter()
+            .map(|(key, item)| CacheEntry {
+                id: item.id,
+                len: key.len(),
+                age: item.created.elapsed().as_secs(),
+                hits: item.hits,
+                pinned: self.pinned.contains(&key.0),
+                preview: preview(&key.0[..key.len().min(PREVIEW_TOKENS)]),
+            })
+            .sorted_by_key(|entry| entry.id)
+            .collect()
+    }
+
+    /// Tokens of all cached prefixes, in the order they are inserted.
+    pub fn prefixes(&self) -> Vec<Vec<u16>> {
+        self.trie
+            .iter()
+            .sorted_by_key(|(_, item)| item.id)
+            .map(|(key, _)| key.0.clone())
+            .collect()
+    }
+
+    fn find(&self, ids: &[usize]) -> Vec<Tokens> {
+        self.trie
+            .iter()
+            .filter(|(_, item)| ids.contains(&item.id))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Remove items of the given ids regardless of whether they are pinned.
+    pub fn remove(&mut self, ids: &[usize]) {
+        for key in self.find(ids) {
+            self.pinned.remove(&key.0);
+            self.trie.remove(&key);
+        }
+    }
+
+    /// Remove all items that are not pinned.
+    pub fn flush(&mut self) {
+        let keys = self
+            .trie
+            .keys()
+            .filter(|key| !self.pinned.contains(&key.0))
+            .cloned()
+            .collect_vec();
+        for key in keys {
+            self.trie.remove(&key);
+        }
+    }
+
+    /// Protect the item of `tokens` from eviction, whether it is already cached or inserted later.
+    pub fn pin(&mut self, tokens: Vec<u16>) {
+        self.pinned.insert(tokens);
+    }
+
+    pub fn unpin(&mut self, ids: &[usize]) {
+        for key in self.find(ids) {
+            self.pinned.remove(&key.0);
+        }
+    }
+
+    fn evict(&mut self) {
+        let max_items = match self.item_size {
+            0 => self.max_items,
+            size => self.max_items.min(self.max_size / size),
+        };
+        while self.trie.count() > max_items {
+            let Some(key) = self
+                .trie
+                .iter()
+                .filter(|(key, _)| !self.pinned.contains(&key.0))
+                .min_by_key(|(_, item)| item.accessed)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            tracing::info!("evicted backed cache of length {}", key.len());
+            self.trie.remove(&key);
+            self.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn tokens(tokens: &[u16]) -> Tokens {
+        Tokens(tokens.to_vec())
+    }
+
+    /// Insert with a pause, so that the access times of the items differ.
+    fn insert(cache: &mut BackedCache<usize>, key: &[u16], state: usize) {
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert(tokens(key), state);
+    }
+
+    fn checkout(cache: &mut BackedCache<usize>, key: &[u16]) -> Option<(Vec<u16>, usize)> {
+        std::thread::sleep(Duration::from_millis(2));
+        cache.checkout(key)
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = BackedCache::new(0, 2, 0);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[2], 2);
+        assert!(checkout(&mut cache, &[1]).is_some());
+        insert(&mut cache, &[3], 3);
+        assert_eq!(cache.prefixes(), vec![vec![1], vec![3]]);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn bounded_by_items() {
+        let mut cache = BackedCache::new(0, 3, 0);
+        for key in 0..10 {
+            insert(&mut cache, &[key], key as usize);
+        }
+        assert_eq!(cache.prefixes(), vec![vec![7], vec![8], vec![9]]);
+        assert_eq!(cache.stats().items, 3);
+        assert_eq!(cache.stats().evictions, 7);
+    }
+
+    #[test]
+    fn bounded_by_size() {
+        let mut cache = BackedCache::new(10, 8, 25);
+        for key in 0..4 {
+            insert(&mut cache, &[key], key as usize);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.items, 2);
+        assert_eq!(stats.size, 20);
+        assert_eq!(cache.prefixes(), vec![vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn count_hits_and_misses() {
+        let mut cache = BackedCache::new(0, 8, 0);
+        insert(&mut cache, &[1, 2], 12);
+        insert(&mut cache, &[1, 2, 3, 4], 1234);
+
+        // the longest cached prefix is found
+        assert_eq!(
+            checkout(&mut cache, &[1, 2, 3, 4, 5]),
+            Some((vec![1, 2, 3, 4], 1234))
+        );
+        assert_eq!(checkout(&mut cache, &[1, 2, 3]), Some((vec![1, 2], 12)));
+        assert_eq!(checkout(&mut cache, &[2, 1]), None);
+        assert_eq!(checkout(&mut cache, &[1]), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        let hits = cache
+            .entries(|_| String::new())
+            .iter()
+            .map(|entry| entry.hits)
+            .collect_vec();
+        assert_eq!(hits, vec![1, 1]);
+    }
+
+    #[test]
+    fn insert_refreshes_state() {
+        let mut cache = BackedCache::new(0, 8, 0);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[1], 2);
+        assert_eq!(cache.stats().items, 1);
+        assert_eq!(checkout(&mut cache, &[1]), Some((vec![1], 2)));
+    }
+
+    fn id(cache: &BackedCache<usize>, key: &[u16]) -> usize {
+        let entries = cache.entries(|_| String::new());
+        let prefixes = cache.prefixes();
+        let index = prefixes.iter().position(|prefix| prefix == key).unwrap();
+        entries[index].id
+    }
+
+    #[test]
+    fn pinned_survive_eviction() {
+        let mut cache = BackedCache::new(0, 2, 0);
+        cache.pin(vec![1]);
+        insert(&mut cache, &[1], 1);
+        for key in 2..6 {
+            insert(&mut cache, &[key], key as usize);
+        }
+        assert_eq!(cache.prefixes(), vec![vec![1], vec![5]]);
+        let entries = cache.entries(|_| String::new());
+        assert!(entries[0].pinned && !entries[1].pinned);
+    }
+
+    #[test]
+    fn pin_before_insert() {
+        let mut cache = BackedCache::new(0, 1, 0);
+        insert(&mut cache, &[2], 2);
+        cache.pin(vec![1]);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[3], 3);
+        assert_eq!(cache.prefixes(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn pinned_survive_flush() {
+        let mut cache = BackedCache::new(0, 8, 0);
+        cache.pin(vec![1]);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[2], 2);
+        cache.flush();
+        assert_eq!(cache.prefixes(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn unpinned_are_evictable() {
+        let mut cache = BackedCache::new(0, 2, 0);
+        cache.pin(vec![1]);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[2], 2);
+        cache.unpin(&[id(&cache, &[1])]);
+        insert(&mut cache, &[3], 3);
+        assert_eq!(cache.prefixes(), vec![vec![2], vec![3]]);
+
+        cache.pin(vec![2]);
+        cache.unpin(&[id(&cache, &[2])]);
+        cache.flush();
+        assert!(cache.prefixes().is_empty());
+    }
+
+    #[test]
+    fn remove_pinned() {
+        let mut cache = BackedCache::new(0, 8, 0);
+        cache.pin(vec![1]);
+        insert(&mut cache, &[1], 1);
+        insert(&mut cache, &[2], 2);
+        cache.remove(&[id(&cache, &[1])]);
+        assert_eq!(cache.prefixes(), vec![vec![2]]);
+
+        // the removed prefix is no longer pinned when it is cached again
+        insert(&mut cache, &[1], 1);
+        cache.flush();
+        assert!(cache.prefixes().is_empty());
+    }
+}
@@ -1,9 +1,14 @@
-use std::path::PathBuf;
+use std::{
+    net::{AddrParseError, SocketAddr},
+    ops::RangeInclusive,
+    path::PathBuf,
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 use web_rwkv::model::{EmbedDevice, Quant};
 
-use crate::ReloadRequest;
+use crate::{Priority, ReloadRequest};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -12,17 +17,78 @@ pub struct Config {
     pub lora: Vec<Lora>,
     pub tokenizer: Tokenizer,
     pub adapter: AdapterOption,
+    /// Additional models served under their names.
+    pub models: Vec<NamedModel>,
     pub setting: Setting,
+    /// Addresses to serve the API on.
+    pub listen: Vec<Listen>,
+    pub tls: Tls,
+    pub log: Log,
+    /// Keys granting access to the API; the API is open if there is none.
+    pub keys: Vec<ApiKey>,
 }
 
 impl From<Config> for ReloadRequest {
     fn from(value: Config) -> Self {
         let Config {
+            model,
+            lora,
+            tokenizer,
+            adapter,
+            ..
+        } = value;
+        NamedModel {
+            name: Default::default(),
+            model,
+            lora,
+            tokenizer,
+            adapter,
+            on_demand: false,
+        }
+        .into()
+    }
+}
+
+impl Config {
+    /// All models of the config, starting with the default one which has an empty name.
+    pub fn catalogue(&self) -> Vec<NamedModel> {
+        let model = NamedModel {
+            name: Default::default(),
+            model: self.model.clone(),
+            lora: self.lora.clone(),
+            tokenizer: self.tokenizer.clone(),
+            adapter: self.adapter,
+            on_demand: false,
+        };
+        std::iter::once(model)
+            .chain(self.models.iter().cloned())
+            .collect()
+    }
+}
+
+/// A model served under `name`, configured in the same way as the default model.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NamedModel {
+    pub name: String,
+    pub model: Model,
+    pub lora: Vec<Lora>,
+    pub tokenizer: Tokenizer,
+    pub adapter: AdapterOption,
+    /// Load the model on the first request instead of at start; a model that fails to load is only retried by reloading it.
+    pub on_demand: bool,
+}
+
+impl From<NamedModel> for ReloadRequest {
+    fn from(value: NamedModel) -> Self {
+        let NamedModel {
+            name,
             model:
                 Model {
                     path: model_path,
                     quant,
                     quant_type,
+                    quant_layers,
                     turbo,
                     token_chunk_size,
                     head_chunk_size,
@@ -30,6 +96,9 @@ impl From<Config> for ReloadRequest {
                     max_batch,
                     embed_layer,
                     embed_device,
+                    max_cache_items,
+                    max_cache_memory,
+                    pinned_prompts,
                 },
             lora,
             tokenizer: Tokenizer {
@@ -39,10 +108,12 @@ impl From<Config> for ReloadRequest {
             ..
         } = value;
         Self {
+            name,
             model_path,
             lora,
             quant,
             quant_type,
+            quant_layers,
             turbo,
             token_chunk_size,
             head_chunk_size,
@@ -50,6 +121,9 @@ impl From<Config> for ReloadRequest {
             max_batch,
             embed_layer,
             embed_device,
+            max_cache_items,
+            max_cache_memory,
+            pinned_prompts,
             tokenizer_path,
             adapter,
         }
@@ -65,6 +139,8 @@ pub struct Model {
     pub quant: usize,
     /// Quantization type (Int8 or NF4).
     pub quant_type: Quant,
+    /// Quantization types of layer ranges, overriding `quant` and `quant_type`.
+    pub quant_layers: Vec<QuantRange>,
     /// Whether to use alternative GEMM kernel to speed-up long prompts.
     pub turbo: bool,
     /// Maximum tokens to be processed in parallel at once.
@@ -79,6 +155,12 @@ pub struct Model {
     pub embed_layer: usize,
     /// Device to put the embed tensor.
     pub embed_device: EmbedDevice,
+    /// Maximum number of states kept in the backed cache.
+    pub max_cache_items: usize,
+    /// Maximum memory (in MiB) used by the backed cache.
+    pub max_cache_memory: usize,
+    /// Prompts whose states are computed at load and never evicted from the backed cache.
+    pub pinned_prompts: Vec<String>,
 }
 
 impl Default for Model {
@@ -87,6 +169,7 @@ impl Default for Model {
             path: Default::default(),
             quant: Default::default(),
             quant_type: Quant::Int8,
+            quant_layers: vec![],
             turbo: true,
             token_chunk_size: 32,
             head_chunk_size: 8192,
@@ -94,10 +177,65 @@ impl Default for Model {
             max_batch: 16,
             embed_layer: 2,
             embed_device: Default::default(),
+            max_cache_items: 256,
+            max_cache_memory: 4096,
+            pinned_prompts: vec![],
+        }
+    }
+}
+
+/// Layers given either as a single index or as an inclusive range like `"0-5"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "LayerRangeRepr", into = "String")]
+pub struct LayerRange(pub RangeInclusive<usize>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayerRangeRepr {
+    Index(usize),
+    Text(String),
+}
+
+impl TryFrom<LayerRangeRepr> for LayerRange {
+    type Error = String;
+
+    fn try_from(value: LayerRangeRepr) -> Result<Self, Self::Error> {
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid layer {x}"))
+        };
+        match value {
+            LayerRangeRepr::Index(index) => Ok(Self(index..=index)),
+            LayerRangeRepr::Text(text) => match text.split_once('-') {
+                Some((start, end)) => match (parse(start)?, parse(end)?) {
+                    (start, end) if start <= end => Ok(Self(start..=end)),
+                    _ => Err(format!("invalid layer range {text}")),
+                },
+                None => parse(&text).map(|index| Self(index..=index)),
+            },
         }
     }
 }
 
+impl From<LayerRange> for String {
+    fn from(value: LayerRange) -> Self {
+        let (start, end) = value.0.into_inner();
+        match start == end {
+            true => format!("{start}"),
+            false => format!("{start}-{end}"),
+        }
+    }
+}
+
+/// Quantization type of a range of layers. The head is always kept in f16.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantRange {
+    pub layers: LayerRange,
+    #[serde(rename = "type")]
+    pub quant_type: Quant,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Lora {
@@ -131,8 +269,199 @@ pub enum AdapterOption {
 }
 
 /// More inference configurations.
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Setting {
     /// Additional stop words.
     pub stop: Vec<String>,
+    /// Maximum number of requests waiting in the queue; `0` for no limit.
+    pub max_queue_depth: usize,
+    /// Maximum seconds a request may wait in the queue; `0` for no limit.
+    pub max_queue_wait: u64,
+    /// Maximum GPU memory (in MiB) taken by all loaded models; `0` for no limit.
+    pub max_model_memory: usize,
+    /// Seconds after which a model not requested and running no request is unloaded; `0` to keep models loaded.
+    pub model_idle_timeout: u64,
+    /// Seconds the inference loop may go without finishing a round before the server is reported not live; `0` for no limit.
+    pub live_timeout: u64,
+    /// Seconds running generations may take to finish on shutdown; `0` for no limit.
+    pub shutdown_timeout: u64,
+    /// File to which the tokens of cached prefixes are saved on shutdown, and computed again when models are loaded; empty to disable.
+    pub cache_path: PathBuf,
+    /// Path to the file keeping the daily token usage of each API key.
+    pub quota_path: PathBuf,
+    /// Path to the append-only log of the usage of all inference requests.
+    pub usage_path: PathBuf,
+}
+
+impl Default for Setting {
+    fn default() -> Self {
+        Self {
+            stop: vec![],
+            max_queue_depth: 0,
+            max_queue_wait: 0,
+            max_model_memory: 0,
+            model_idle_timeout: 0,
+            live_timeout: 60,
+            shutdown_timeout: 30,
+            cache_path: Default::default(),
+            quota_path: "assets/usage/quota.json".into(),
+            usage_path: "assets/usage/usage.jsonl".into(),
+        }
+    }
+}
+
+/// An address to serve the API on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Listen {
+    /// A TCP address, served with HTTPS if TLS is enabled.
+    Tcp { addr: SocketAddr },
+    /// A Unix domain socket, always served with plain HTTP.
+    Unix {
+        path: PathBuf,
+        /// Permission bits of the socket file, e.g., `0o660`; left to the umask if not given.
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+}
+
+impl FromStr for Listen {
+    type Err = AddrParseError;
+
+    /// Parse either `IP:PORT` or `unix:PATH`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix {
+                path: path.into(),
+                mode: None,
+            }),
+            None => s.parse().map(|addr| Self::Tcp { addr }),
+        }
+    }
+}
+
+/// HTTPS serving with a certificate and private key in PEM format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tls {
+    /// Whether to serve HTTPS instead of HTTP.
+    pub enable: bool,
+    /// Path to the certificate chain.
+    pub cert: PathBuf,
+    /// Path to the private key.
+    pub key: PathBuf,
+    /// Port on which plain HTTP requests are redirected to HTTPS; `0` for no redirect.
+    pub redirect_port: u16,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            cert: "assets/certs/cert.pem".into(),
+            key: "assets/certs/key.pem".into(),
+            redirect_port: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line, with the fields of the events and their spans.
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Log {
+    /// Filter directives of log levels, e.g. `warn,ai00_server=info`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: "warn,ai00_server=info".into(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// Routes an API key is allowed to access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Completions, chat completions, embeddings and cancelling requests.
+    Inference,
+    /// Loading, unloading and inspecting models and their caches.
+    Model,
+    /// Listing, unzipping files and reading or writing configs.
+    File,
+}
+
+/// A key granting access to the API, sent as a bearer token.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiKey {
+    pub key: String,
+    /// Identity of the client holding the key, used for fair scheduling and accounting; a digest of the key if empty.
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Highest priority of the requests sent with the key; requests may only ask for a lower one.
+    pub priority: Priority,
+    /// Maximum inference requests per minute; `0` for no limit.
+    pub requests_per_minute: usize,
+    /// Maximum tokens per minute; `0` for no limit.
+    pub tokens_per_minute: usize,
+    /// Maximum tokens per day (UTC); `0` for no limit.
+    pub tokens_per_day: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: serde_json::Value) -> Result<LayerRange, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    #[test]
+    fn layer_range_single() {
+        assert_eq!(parse(5.into()).unwrap().0, 5..=5);
+        assert_eq!(parse("5".into()).unwrap().0, 5..=5);
+        assert_eq!(parse(" 7 ".into()).unwrap().0, 7..=7);
+    }
+
+    #[test]
+    fn layer_range_span() {
+        assert_eq!(parse("0-5".into()).unwrap().0, 0..=5);
+        assert_eq!(parse(" 2 - 4 ".into()).unwrap().0, 2..=4);
+        assert_eq!(parse("3-3".into()).unwrap().0, 3..=3);
+    }
+
+    #[test]
+    fn layer_range_reversed() {
+        assert!(parse("5-2".into()).is_err());
+    }
+
+    #[test]
+    fn layer_range_empty() {
+        assert!(parse("".into()).is_err());
+        assert!(parse("-".into()).is_err());
+        assert!(parse("-3".into()).is_err());
+        assert!(parse("3-".into()).is_err());
+        assert!(parse("a-b".into()).is_err());
+    }
+
+    #[test]
+    fn layer_range_display() {
+        assert_eq!(String::from(LayerRange(4..=4)), "4");
+        assert_eq!(String::from(LayerRange(0..=5)), "0-5");
+    }
 }
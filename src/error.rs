@@ -0,0 +1,174 @@
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{sse::Event, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::QueueError;
+
+/// Seconds suggested to clients before they retry a request failed while no model can serve it.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Errors returned by the handlers, rendered as OpenAI-style error objects.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// No model is loaded, or the loading has failed.
+    ModelNotLoaded,
+    /// No model has the name or id asked for.
+    ModelNotFound(String),
+    /// The queue is at its maximum depth; retry after the seconds.
+    QueueFull(u64),
+    /// The API key has exceeded one of its rate limits or quotas.
+    RateLimited(String),
+    /// The request has waited in the queue for too long; retry after the seconds.
+    QueueTimeout(u64),
+    /// The server is shutting down and admits no more requests.
+    ShuttingDown,
+    /// The request is malformed or has invalid parameters.
+    BadRequest(String),
+    /// The request has no valid API key.
+    Unauthorized(String),
+    /// The request is not allowed to access the resource.
+    Forbidden(String),
+    /// The resource requested does not exist.
+    NotFound(String),
+    /// Something went wrong on the server.
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    param: Option<String>,
+    code: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::ModelNotLoaded => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::QueueFull(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::QueueTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The `code` field of the error object.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::ModelNotLoaded => "model_not_loaded",
+            ApiError::ModelNotFound(_) => "model_not_found",
+            ApiError::QueueFull(_) => "queue_full",
+            ApiError::RateLimited(_) => "rate_limit_exceeded",
+            ApiError::QueueTimeout(_) => "queue_timeout",
+            ApiError::ShuttingDown => "shutting_down",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "invalid_api_key",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The `type` field of the error object.
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::ModelNotLoaded
+            | ApiError::QueueTimeout(_)
+            | ApiError::ShuttingDown
+            | ApiError::Internal(_) => "server_error",
+            ApiError::QueueFull(_) | ApiError::RateLimited(_) => "rate_limit_error",
+            ApiError::Forbidden(_) => "permission_error",
+            ApiError::ModelNotFound(_)
+            | ApiError::BadRequest(_)
+            | ApiError::Unauthorized(_)
+            | ApiError::NotFound(_) => "invalid_request_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::ModelNotLoaded => "no model is loaded".into(),
+            ApiError::ModelNotFound(model) => format!("the model `{model}` does not exist"),
+            ApiError::QueueFull(_) => "too many requests in the queue".into(),
+            ApiError::QueueTimeout(_) => "request waited in the queue for too long".into(),
+            ApiError::ShuttingDown => "the server is shutting down".into(),
+            ApiError::RateLimited(message)
+            | ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Internal(message) => message.clone(),
+        }
+    }
+
+    /// Seconds after which the client may retry, sent as the `Retry-After` header.
+    /// Rate limits set their own header, telling when the window of the limit resets.
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::QueueFull(secs) | ApiError::QueueTimeout(secs) => Some(*secs),
+            ApiError::ModelNotLoaded | ApiError::ShuttingDown => Some(RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+
+    fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorBody {
+                message: self.message(),
+                kind: self.kind(),
+                param: None,
+                code: self.code(),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl From<QueueError> for ApiError {
+    fn from(value: QueueError) -> Self {
+        match value {
+            QueueError::Full(retry) => ApiError::QueueFull(retry.as_secs_f64().ceil() as u64),
+            QueueError::Timeout(retry) => ApiError::QueueTimeout(retry.as_secs_f64().ceil() as u64),
+            QueueError::Shutdown => ApiError::ShuttingDown,
+        }
+    }
+}
+
+impl From<ApiError> for Event {
+    fn from(value: ApiError) -> Self {
+        let data = serde_json::to_string(&value.body()).unwrap_or_default();
+        Event::default().event("error").data(data)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let body = Json(self.body());
+        match self.retry_after() {
+            Some(secs) => (status, [(RETRY_AFTER, secs.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
+    }
+}
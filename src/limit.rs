@@ -0,0 +1,349 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{HeaderName, RETRY_AFTER},
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{config::ApiKey, error::ApiError, TokenCounter};
+
+/// Span of the per-minute limits.
+const WINDOW: Duration = Duration::from_secs(60);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// How often the daily usage is written to disk if changed.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tokens used by a key in a day (UTC), which is persisted.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct DailyUsage {
+    /// Days since the Unix epoch.
+    day: u64,
+    tokens: usize,
+}
+
+#[derive(Debug, Default)]
+struct KeyUsage {
+    /// Admission times of requests in the last minute.
+    requests: VecDeque<Instant>,
+    /// Tokens used in the last minute.
+    tokens: VecDeque<(Instant, usize)>,
+    daily: DailyUsage,
+}
+
+impl KeyUsage {
+    fn prune(&mut self, now: Instant, today: u64) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        while self
+            .tokens
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) >= WINDOW)
+        {
+            self.tokens.pop_front();
+        }
+        if self.daily.day != today {
+            self.daily = DailyUsage {
+                day: today,
+                tokens: 0,
+            };
+        }
+    }
+}
+
+/// A limit, with what is left of it and when it resets.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    limit: usize,
+    remaining: usize,
+    reset: Duration,
+}
+
+impl Window {
+    fn new(limit: usize, used: usize, reset: Duration) -> Option<Self> {
+        (limit > 0).then_some(Self {
+            limit,
+            remaining: limit.saturating_sub(used),
+            reset,
+        })
+    }
+}
+
+/// State of the limits of a key, reported in the `x-ratelimit-*` headers.
+#[derive(Debug, Default, Clone, Copy)]
+struct RateStatus {
+    requests: Option<Window>,
+    tokens: Option<Window>,
+    daily: Option<Window>,
+}
+
+impl RateStatus {
+    fn write(&self, headers: &mut HeaderMap) {
+        let windows = [
+            (self.requests, "requests"),
+            (self.tokens, "tokens"),
+            (self.daily, "tokens-day"),
+        ];
+        for (window, name) in windows {
+            let Some(window) = window else {
+                continue;
+            };
+            let values = [
+                ("limit", window.limit.to_string()),
+                ("remaining", window.remaining.to_string()),
+                ("reset", format!("{}s", window.reset.as_secs_f32().ceil())),
+            ];
+            for (field, value) in values {
+                let name = HeaderName::try_from(format!("x-ratelimit-{field}-{name}"));
+                if let (Ok(name), Ok(value)) = (name, HeaderValue::try_from(value)) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+    }
+
+    /// The exhausted limit with the latest reset, if any.
+    fn exceeded(&self) -> Option<(&'static str, Window)> {
+        [
+            (self.requests, "requests per minute"),
+            (self.tokens, "tokens per minute"),
+            (self.daily, "tokens per day"),
+        ]
+        .into_iter()
+        .filter_map(|(window, name)| window.map(|window| (name, window)))
+        .filter(|(_, window)| window.remaining == 0)
+        .max_by_key(|(_, window)| window.reset)
+    }
+}
+
+/// A request rejected for the exhausted limit `name`.
+#[derive(Debug, Clone, Copy)]
+struct Rejection {
+    status: RateStatus,
+    name: &'static str,
+    window: Window,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let Self {
+            status,
+            name,
+            window,
+        } = self;
+        let retry = window.reset.as_secs_f32().ceil();
+        let error = ApiError::RateLimited(format!(
+            "rate limit of {} {} reached, retry in {} seconds",
+            window.limit, name, retry
+        ));
+
+        let mut response = error.into_response();
+        let headers = response.headers_mut();
+        if let Ok(retry) = HeaderValue::try_from(retry.to_string()) {
+            headers.insert(RETRY_AFTER, retry);
+        }
+        status.write(headers);
+        response
+    }
+}
+
+/// Days since the Unix epoch, and the time until the next day.
+fn today() -> (u64, Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let day = now / SECS_PER_DAY;
+    let reset = Duration::from_secs((day + 1) * SECS_PER_DAY - now);
+    (day, reset)
+}
+
+/// Keys are tracked by the digests of their tokens, so that the tokens are not written to disk.
+pub(crate) fn digest(key: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.update(key.as_bytes());
+    format!("{:x}", sha.finalize())
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    keys: HashMap<String, KeyUsage>,
+    /// Whether the daily usage has changed since last persisted.
+    dirty: bool,
+}
+
+/// Enforces the rate limits and quotas of API keys.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    path: PathBuf,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl Limiter {
+    /// Create a limiter with the daily usage persisted at `path`, which is written back periodically.
+    pub fn new(path: PathBuf) -> Self {
+        let mut state = LimiterState::default();
+        match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice::<HashMap<String, DailyUsage>>(&data) {
+                Ok(usage) => {
+                    state.keys = usage
+                        .into_iter()
+                        .map(|(key, daily)| {
+                            let usage = KeyUsage {
+                                daily,
+                                ..Default::default()
+                            };
+                            (key, usage)
+                        })
+                        .collect()
+                }
+                Err(err) => tracing::error!("failed to parse quota usage: {}", err),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::error!("failed to read quota usage: {}", err),
+        }
+
+        let limiter = Self {
+            path,
+            state: Arc::new(Mutex::new(state)),
+        };
+        let task = {
+            let limiter = limiter.clone();
+            async move {
+                let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = limiter.persist() {
+                        tracing::error!("failed to save quota usage: {}", err);
+                    }
+                }
+            }
+        };
+        tokio::spawn(task);
+        limiter
+    }
+
+    /// Write the daily usage to disk if it has changed.
+    pub fn persist(&self) -> Result<()> {
+        let usage: HashMap<_, _> = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            state
+                .keys
+                .iter()
+                .map(|(key, usage)| (key.clone(), usage.daily))
+                .collect()
+        };
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec(&usage)?)?;
+        Ok(())
+    }
+
+    /// Count a request of the key if none of its limits is exhausted, otherwise return the exhausted limit.
+    fn admit(&self, digest: &str, key: &ApiKey) -> Result<RateStatus, Box<Rejection>> {
+        let now = Instant::now();
+        let (day, day_reset) = today();
+
+        let mut state = self.state.lock().unwrap();
+        let usage = state.keys.entry(digest.into()).or_default();
+        usage.prune(now, day);
+
+        let reset = |time: Option<&Instant>| {
+            time.map(|time| WINDOW.saturating_sub(now.duration_since(*time)))
+                .unwrap_or_default()
+        };
+        let tokens = usage.tokens.iter().map(|(_, tokens)| tokens).sum();
+        let mut status = RateStatus {
+            requests: Window::new(
+                key.requests_per_minute,
+                usage.requests.len(),
+                reset(usage.requests.front()),
+            ),
+            tokens: Window::new(
+                key.tokens_per_minute,
+                tokens,
+                reset(usage.tokens.front().map(|(time, _)| time)),
+            ),
+            daily: Window::new(key.tokens_per_day, usage.daily.tokens, day_reset),
+        };
+        if let Some((name, window)) = status.exceeded() {
+            return Err(Box::new(Rejection {
+                status,
+                name,
+                window,
+            }));
+        }
+
+        usage.requests.push_back(now);
+        if let Some(window) = &mut status.requests {
+            window.remaining -= 1;
+            window.reset = reset(usage.requests.front());
+        }
+        Ok(status)
+    }
+
+    fn record(&self, digest: &str, tokens: usize) {
+        let now = Instant::now();
+        let (day, _) = today();
+
+        let mut state = self.state.lock().unwrap();
+        let usage = state.keys.entry(digest.into()).or_default();
+        usage.prune(now, day);
+        usage.tokens.push_back((now, tokens));
+        usage.daily.tokens += tokens;
+        state.dirty = true;
+    }
+}
+
+/// Counts the tokens of a request against the limits of its key.
+#[derive(Debug, Clone)]
+pub struct Meter {
+    limiter: Limiter,
+    digest: String,
+}
+
+impl Meter {
+    pub fn record(&self, counter: &TokenCounter) {
+        self.limiter.record(&self.digest, counter.total_tokens);
+    }
+}
+
+/// Reject requests of keys whose limits are exhausted, and report the limits in the `x-ratelimit-*` headers.
+pub async fn limit(State(limiter): State<Limiter>, mut request: Request, next: Next) -> Response {
+    let Some(key) = request.extensions().get::<ApiKey>().cloned() else {
+        return next.run(request).await;
+    };
+
+    let digest = digest(&key.key);
+    let status = match limiter.admit(&digest, &key) {
+        Ok(status) => status,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    request.extensions_mut().insert(Meter { limiter, digest });
+    let mut response = next.run(request).await;
+    status.write(response.headers_mut());
+    response
+}
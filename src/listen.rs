@@ -0,0 +1,187 @@
+use std::{
+    future::{Future, IntoFuture},
+    io,
+    net::SocketAddr,
+};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::task::JoinHandle;
+
+use crate::config::Listen;
+
+/// Identity of a connection over a Unix domain socket, which has no address to tell its client apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connection(pub u64);
+
+/// Serve the app at the address until `signal` resolves, after which the running requests are waited for.
+/// TCP addresses are served with HTTPS if `rustls` is given.
+pub async fn serve<F>(
+    listen: Listen,
+    app: Router,
+    rustls: Option<RustlsConfig>,
+    signal: F,
+) -> io::Result<JoinHandle<io::Result<()>>>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match (listen, rustls) {
+        (Listen::Tcp { addr }, None) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!("server started at http://{addr}");
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            let server = axum::serve(listener, app).with_graceful_shutdown(signal);
+            Ok(tokio::spawn(server.into_future()))
+        }
+        (Listen::Tcp { addr }, Some(rustls)) => {
+            let handle = axum_server::Handle::new();
+            let task = {
+                let handle = handle.clone();
+                async move {
+                    signal.await;
+                    handle.graceful_shutdown(None);
+                }
+            };
+            tokio::spawn(task);
+
+            tracing::info!("server started at https://{addr}");
+            let server = axum_server::bind_rustls(addr, rustls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+            Ok(tokio::spawn(server))
+        }
+        #[cfg(unix)]
+        (Listen::Unix { path, mode }, _) => {
+            let listener = unix::bind(&path, mode)?;
+            tracing::info!("server started at unix:{}", path.to_string_lossy());
+            Ok(tokio::spawn(unix::serve(listener, path, app, signal)))
+        }
+        #[cfg(not(unix))]
+        (Listen::Unix { .. }, _) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix domain sockets are not supported on this platform",
+        )),
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs::Permissions,
+        future::Future,
+        io,
+        os::unix::fs::{FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+    };
+
+    use axum::{http::Request, Router};
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto::Builder, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+    use tokio::net::UnixListener;
+    use tower::ServiceExt;
+
+    use super::Connection;
+
+    /// Bind the socket at `path`, replacing a stale one left by an earlier run, and set its permission bits.
+    ///
+    /// With a mode, the socket is bound inside a private temporary directory and moved into place
+    /// after its permissions are set, so that it is never reachable with the permissions of the umask.
+    pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+        remove_stale(path)?;
+        let Some(mode) = mode else {
+            return UnixListener::bind(path);
+        };
+
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = tempfile::Builder::new()
+            .prefix(".ai00-")
+            .tempdir_in(parent)?;
+        let temp = dir.path().join("socket");
+        let listener = UnixListener::bind(&temp)?;
+        std::fs::set_permissions(&temp, Permissions::from_mode(mode))?;
+        std::fs::rename(&temp, path)?;
+        Ok(listener)
+    }
+
+    /// Remove the socket at `path` if no server is listening on it.
+    /// Anything else at the path is left alone and reported as an error.
+    fn remove_stale(path: &Path) -> io::Result<()> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            )),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn serve<F>(
+        listener: UnixListener,
+        path: PathBuf,
+        app: Router,
+        signal: F,
+    ) -> io::Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let builder = Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(signal);
+
+        let mut connections = 0;
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        tracing::error!("failed to accept connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = &mut signal => break,
+            };
+            connections += 1;
+            let connection = Connection(connections);
+            let app = app.clone().map_request(move |mut request: Request<_>| {
+                request.extensions_mut().insert(connection);
+                request
+            });
+            let service = TowerToHyperService::new(app);
+            let connection = builder
+                .serve_connection(TokioIo::new(stream), service)
+                .into_owned();
+            let connection = graceful.watch(connection);
+            let task = async move {
+                if let Err(err) = connection.await {
+                    tracing::debug!("connection closed: {}", err);
+                }
+            };
+            tokio::spawn(task);
+        }
+
+        drop(listener);
+        graceful.shutdown().await;
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
@@ -1,27 +1,33 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, File},
     io::{BufReader, Cursor, Read},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::{
-    routing::{get, post},
-    Router,
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
 };
 use clap::Parser;
-use config::{AdapterOption, Config, Setting};
+use config::{AdapterOption, Config, Listen, NamedModel, Scope, Setting, Tls};
 use flume::{Receiver, Sender};
+use futures_util::FutureExt;
 use itertools::Itertools;
 use memmap2::Mmap;
 use run::RuntimeUntyped;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
 use tower_http::{cors::CorsLayer, services::ServeDir};
+use tracing::Span;
 use web_rwkv::{
     context::{Context, ContextBuilder, Instance},
     model::{
@@ -29,19 +35,33 @@ use web_rwkv::{
         ModelVersion, Quant, StateBuilder,
     },
     tokenizer::Tokenizer,
-    wgpu::{Backends, PowerPreference},
+    wgpu::{Adapter, Backends, PowerPreference},
 };
 
 use crate::{
-    run::{GenerateContext, Runtime, SlotResult, Tokens},
+    cache::{CacheRequest, CacheResponse, CacheStats},
+    error::ApiError,
+    run::{GenerateContext, InFlight, Pulse, Runtime, SlotResult, SlotsInfo},
     sampler::Sampler,
 };
 
 mod api;
+mod auth;
+mod cache;
 mod config;
+mod error;
+mod limit;
+mod listen;
+mod memory;
+mod metric;
 mod oai;
+mod overrides;
 mod run;
 mod sampler;
+mod shutdown;
+mod tls;
+mod trace;
+mod usage;
 mod utils;
 
 pub const MAX_TOKENS: usize = 4096;
@@ -49,6 +69,10 @@ pub const STATE_CHUNK_SIZE: usize = 4;
 
 #[derive(Debug)]
 pub enum Token {
+    /// The request waits in the queue at the position.
+    Queue(usize),
+    /// The request is rejected from the queue.
+    Reject(QueueError),
     Start,
     Token(String),
     Stop(FinishReason, TokenCounter),
@@ -56,7 +80,18 @@ pub enum Token {
     Done,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize)]
+/// Why a request is rejected from the queue, with the time the client is suggested to wait before retrying.
+#[derive(Debug, Clone, Copy)]
+pub enum QueueError {
+    /// The queue is at its maximum depth.
+    Full(Duration),
+    /// The request has waited in the queue for too long.
+    Timeout(Duration),
+    /// The server is shutting down.
+    Shutdown,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum FinishReason {
     /// API returned complete model output.
@@ -65,6 +100,8 @@ pub enum FinishReason {
     Length,
     /// Omitted content due to a flag from our content filters.
     ContentFilter,
+    /// Generation cancelled by the user.
+    Cancelled,
     /// API response still in progress or incomplete.
     #[default]
     Null,
@@ -95,24 +132,66 @@ where
 #[derive(Debug, Clone)]
 pub enum ThreadRequest {
     Adapter(Sender<AdapterList>),
-    Info(Sender<RuntimeInfo>),
+    Info {
+        model: String,
+        sender: Sender<RuntimeInfo>,
+    },
+    Models(Sender<Vec<ReloadRequest>>),
+    /// A client is about to use the model, which is loaded if it is not; replies the progress of its loading.
+    Demand {
+        model: String,
+        sender: Sender<Result<watch::Receiver<LoadProgress>, ApiError>>,
+    },
     Generate {
-        request: GenerateRequest,
+        request: Box<GenerateRequest>,
         tokenizer: Arc<Tokenizer>,
         sender: Sender<Token>,
+        /// Span of the HTTP request, which the logs of the generation are put under.
+        span: Span,
     },
     Reload {
         request: ReloadRequest,
-        sender: Option<Sender<bool>>,
+        /// Memory held for the model in the budget, which is set by the dispatcher.
+        memory: Option<Arc<HeldMemory>>,
+        sender: Option<Sender<LoadProgress>>,
+    },
+    Unload {
+        model: String,
+        sender: Sender<()>,
+    },
+    /// Register a LoRA variant of a model; replies `false` if the base model is unknown.
+    Lora {
+        variant: LoraVariant,
+        sender: Sender<bool>,
     },
-    Unload,
+    Cache {
+        model: String,
+        request: CacheRequest,
+        sender: Sender<CacheResponse>,
+    },
+    Cancel {
+        id: String,
+        sender: Sender<bool>,
+    },
+    Slots {
+        model: String,
+        sender: Sender<SlotsInfo>,
+    },
+    /// Collect the progress of the inference loops of all models.
+    Pulse(Sender<ModelPulse>),
+    /// Reject queued and new requests for shutdown, while running ones go on.
+    Close,
+    /// Collect the tokens of the cached prefixes of all models by their names.
+    Dump(Sender<(String, Vec<Vec<u16>>)>),
 }
 
 #[derive(Default)]
 pub enum Environment<'a> {
     Loaded {
         runtime: RuntimeUntyped<'a>,
-        reload: ReloadRequest,
+        reload: Box<ReloadRequest>,
+        /// Released after the runtime is drained.
+        memory: Option<Arc<HeldMemory>>,
     },
     #[default]
     None,
@@ -123,18 +202,44 @@ impl Environment<'_> {
         let mut queue = vec![];
         match self {
             Environment::Loaded { runtime, .. } => match runtime.queue(context).await {
-                SlotResult::Success(batch) => log::info!("queued task at slot {batch}"),
-                SlotResult::Fault(batch) => log::info!("swapped task at slot {batch}"),
+                SlotResult::Success(batch) => tracing::info!("queued task at slot {batch}"),
+                SlotResult::Fault(batch) => tracing::info!("swapped task at slot {batch}"),
                 SlotResult::Failure(context) => {
-                    log::info!("failed to queue task");
+                    tracing::info!("failed to queue task");
                     queue.push(*context);
                 }
-                SlotResult::Error => log::error!("empty task is not queued"),
+                SlotResult::Error => tracing::error!("empty task is not queued"),
             },
             Environment::None => queue.push(context),
         };
         queue
     }
+
+    pub async fn cancel(&self, id: &str) -> bool {
+        match self {
+            Environment::Loaded { runtime, .. } => runtime.cancel(id).await,
+            Environment::None => false,
+        }
+    }
+
+    pub async fn cache(&self, request: CacheRequest) -> Result<CacheResponse> {
+        match self {
+            Environment::Loaded { runtime, .. } => runtime.cache(request).await,
+            Environment::None => bail!("model not loaded"),
+        }
+    }
+}
+
+/// Progress of the inference loop of a model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelPulse {
+    pub model: String,
+    /// Whether the loop is processing requests, rather than waiting for them.
+    pub busy: bool,
+    /// Seconds since the loop last made progress.
+    pub stalled: f32,
+    /// Whether the loop is idle or has made progress within `live_timeout`.
+    pub live: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -142,13 +247,50 @@ pub struct RuntimeInfo {
     pub reload: ReloadRequest,
     pub model: ModelInfo,
     pub tokenizer: Arc<Tokenizer>,
+    pub cache: CacheStats,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct AdapterList(pub Vec<String>);
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Latency-sensitive requests, e.g., chat.
+    #[default]
+    Interactive,
+    /// Throughput-oriented requests, e.g., bulk jobs.
+    Batch,
+}
+
+impl Priority {
+    /// Relative share of runtime batches of a client sending requests of this priority.
+    pub fn weight(&self) -> f32 {
+        match self {
+            Priority::Interactive => 4.0,
+            Priority::Batch => 1.0,
+        }
+    }
+
+    /// This priority, lowered to `max` if it is higher.
+    pub fn cap(self, max: Priority) -> Priority {
+        match (self, max) {
+            (Priority::Interactive, Priority::Batch) => Priority::Batch,
+            (priority, _) => priority,
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct GenerateRequest {
+    /// Unique id of the request, used for cancellation.
+    pub id: String,
+    /// Name or id of the model to run the request; empty for the default model.
+    pub model: String,
+    /// Identity of the client, used for fair scheduling: the name of its API key, or else its address.
+    pub client: String,
+    /// Scheduling class of the request.
+    pub priority: Priority,
     /// The prompt for the model.
     pub prompt: String,
     /// All text the model output earlier.
@@ -163,11 +305,15 @@ pub struct GenerateRequest {
     pub logit_bias: HashMap<u16, f32>,
     /// Whether this is an embedding request.
     pub embed: bool,
+    /// Accounts the tokens of the request when it finishes, even if its client has gone.
+    pub usage: Option<usage::Usage>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ReloadRequest {
+    /// Name of the model to be loaded or replaced; empty for the default model.
+    pub name: String,
     /// Path to the model.
     pub model_path: PathBuf,
     /// List of LoRA blended on the model.
@@ -176,6 +322,8 @@ pub struct ReloadRequest {
     pub quant: usize,
     /// Quantization type (Int8 or NF4).
     pub quant_type: Quant,
+    /// Quantization types of layer ranges, overriding `quant` and `quant_type`.
+    pub quant_layers: Vec<config::QuantRange>,
     /// Whether to use alternative GEMM kernel to speed-up long prompts.
     pub turbo: bool,
     /// Maximum tokens to be processed in parallel at once.
@@ -190,6 +338,12 @@ pub struct ReloadRequest {
     pub embed_layer: usize,
     /// Device to put the embed tensor.
     pub embed_device: EmbedDevice,
+    /// Maximum number of states kept in the backed cache.
+    pub max_cache_items: usize,
+    /// Maximum memory (in MiB) used by the backed cache.
+    pub max_cache_memory: usize,
+    /// Prompts whose states are computed at load and never evicted from the backed cache.
+    pub pinned_prompts: Vec<String>,
     /// Path to the tokenizer.
     pub tokenizer_path: PathBuf,
     /// Adapter selection.
@@ -202,7 +356,135 @@ impl Default for ReloadRequest {
     }
 }
 
-#[derive(Debug, Default, Clone, Serialize)]
+impl ReloadRequest {
+    /// Quantization type of each quantized layer: the first `quant` layers, then overridden by `quant_layers` in order.
+    /// Layers past the `num_layer` of the model are ignored.
+    pub fn quant_map(&self, num_layer: usize) -> HashMap<usize, Quant> {
+        let mut map: HashMap<_, _> = (0..self.quant.min(num_layer))
+            .map(|layer| (layer, self.quant_type))
+            .collect();
+        for range in &self.quant_layers {
+            let (start, end) = range.layers.0.clone().into_inner();
+            for layer in start..end.saturating_add(1).min(num_layer) {
+                map.insert(layer, range.quant_type);
+            }
+        }
+        map.retain(|_, quant| !matches!(quant, Quant::None));
+        map
+    }
+
+    /// Id shown to clients, which is the file stem of the model if it is the default one.
+    pub fn model_id(&self) -> String {
+        match self.name.is_empty() {
+            true => self
+                .model_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into())
+                .unwrap_or_default(),
+            false => self.name.clone(),
+        }
+    }
+}
+
+/// A model with additional LoRA blended, served under its own name.
+///
+/// LoRA are blended into the weights when a model is built, so each variant is a separate copy of the model
+/// that is loaded on demand and unloaded like any other. Nothing is shared with the base model: a loaded variant
+/// takes as much GPU memory as a full model, and is counted so against `max_model_memory`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoraVariant {
+    /// Name of the variant, by which requests select it.
+    pub name: String,
+    /// Name of the base model; empty for the default model.
+    pub model: String,
+    /// List of LoRA blended on top of those of the base model.
+    pub lora: Vec<config::Lora>,
+    /// Load the variant now instead of on the first request.
+    pub preload: bool,
+}
+
+/// Phases of loading a model, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadPhase {
+    /// Reading the model file from disk.
+    Read,
+    /// Creating the GPU context.
+    Context,
+    /// Reading the tokenizer.
+    Tokenizer,
+    /// Reading LoRA files.
+    Lora,
+    /// Uploading tensors, quantizing and blending LoRA, which web-rwkv does in one go without reporting back.
+    Build,
+    /// Running a token through the model to check inference.
+    Probe,
+    /// Computing the states of pinned prompts.
+    Pin,
+    Done,
+    Failed,
+}
+
+/// The phase a load has reached.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadProgress {
+    pub phase: LoadPhase,
+    /// Progress within the phase in percent, for the phases whose progress is measured:
+    /// [`LoadPhase::Read`] by bytes and [`LoadPhase::Lora`] by files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f32>,
+    /// Reason of the failure if the phase is [`LoadPhase::Failed`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Reports the phases of a reload to the log and to the listener, if any.
+#[derive(Clone)]
+struct LoadReporter {
+    sender: Option<Sender<LoadProgress>>,
+    phase: Option<LoadPhase>,
+}
+
+impl LoadReporter {
+    fn new(sender: Option<Sender<LoadProgress>>) -> Self {
+        Self {
+            sender,
+            phase: None,
+        }
+    }
+
+    fn send(&self, progress: LoadProgress) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(progress);
+        }
+    }
+
+    /// Report entering the phase, or the progress within it.
+    fn report(&mut self, phase: LoadPhase, percent: Option<f32>) {
+        if self.phase != Some(phase) {
+            tracing::info!("loading model: {:?}", phase);
+        }
+        self.phase = Some(phase);
+        self.send(LoadProgress {
+            phase,
+            percent,
+            error: None,
+        });
+    }
+
+    fn fail(&mut self, err: &anyhow::Error) {
+        tracing::error!("reload model failed: {}", err);
+        self.phase = Some(LoadPhase::Failed);
+        self.send(LoadProgress {
+            phase: LoadPhase::Failed,
+            percent: None,
+            error: Some(err.to_string()),
+        });
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct TokenCounter {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
@@ -223,7 +505,7 @@ fn list_adapters() -> AdapterList {
     AdapterList(list)
 }
 
-async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Context> {
+async fn select_adapter(adapter: AdapterOption) -> Result<Adapter> {
     let backends = Backends::all();
     let instance = Instance::new();
     let adapter = match adapter {
@@ -231,6 +513,11 @@ async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Cont
         AdapterOption::Economical => instance.adapter(PowerPreference::LowPower).await,
         AdapterOption::Manual(selection) => instance.select_adapter(backends, selection),
     }?;
+    Ok(adapter)
+}
+
+async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Context> {
+    let adapter = select_adapter(adapter).await?;
     let context = ContextBuilder::new(adapter)
         .with_auto_limits(info)
         .build()
@@ -246,32 +533,62 @@ fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
     Ok(Tokenizer::new(&contents)?)
 }
 
-fn load_model<M, S>(context: &Context, request: ReloadRequest, data: &[u8]) -> Result<(M, S)>
+/// Touch every page of the mapped model so that it is read from disk, reporting the progress.
+fn prefetch(data: &[u8], reporter: &mut LoadReporter) {
+    const PAGE_SIZE: usize = 4096;
+    const CHUNK_SIZE: usize = 64 << 20;
+
+    let mut sum = 0u8;
+    for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        sum = chunk
+            .iter()
+            .step_by(PAGE_SIZE)
+            .fold(sum, |acc, x| acc.wrapping_add(*x));
+        let read = ((index + 1) * CHUNK_SIZE).min(data.len());
+        reporter.report(
+            LoadPhase::Read,
+            Some(100.0 * read as f32 / data.len() as f32),
+        );
+    }
+    std::hint::black_box(sum);
+}
+
+fn load_model<M, S>(
+    context: &Context,
+    request: ReloadRequest,
+    data: &[u8],
+    reporter: &mut LoadReporter,
+) -> Result<(M, S)>
 where
     S: ModelState,
     M: Model<State = S>,
 {
+    let quant = request.quant_map(Loader::info(data)?.num_layer);
     let ReloadRequest {
-        quant,
-        quant_type,
         lora,
         token_chunk_size,
         turbo,
         embed_device,
         ..
     } = request;
-    let quant = (0..quant).map(|layer| (layer, quant_type)).collect();
 
+    let count = lora.len();
     let lora: Vec<Lora> = lora
         .into_iter()
-        .map(|lora| -> Result<Lora> {
+        .enumerate()
+        .map(|(index, lora)| -> Result<Lora> {
             let file = File::open(&lora.path)?;
             let data = unsafe { Mmap::map(&file) }?.to_vec();
             let blend = LoraBlend::full(lora.alpha);
+            reporter.report(
+                LoadPhase::Lora,
+                Some(100.0 * (index + 1) as f32 / count as f32),
+            );
             Ok(Lora { data, blend })
         })
         .try_collect()?;
 
+    reporter.report(LoadPhase::Build, None);
     let model = ModelBuilder::new(context, data)
         .with_quant(quant)
         .with_turbo(turbo)
@@ -289,6 +606,69 @@ where
     Ok((model, state))
 }
 
+/// Pin the prompts in the backed cache, and queue them so that their states are computed.
+async fn pin_prompts(
+    env: &RwLock<Environment<'_>>,
+    queue: &Mutex<Vec<GenerateContext>>,
+    prompts: Vec<String>,
+) -> Result<CacheResponse> {
+    // keep the same lock order as the dequeue task: queue first, then environment
+    let mut queue = queue.lock().await;
+    let env = env.read().await;
+    let Environment::Loaded { runtime, .. } = &*env else {
+        bail!("model not loaded")
+    };
+    let response = runtime.cache(CacheRequest::Pin(prompts.clone())).await?;
+    warm_prompts(&env, &mut queue, prompts).await?;
+    Ok(response)
+}
+
+/// Queue the prefixes cached by the model at the last shutdown so that their states are computed again.
+async fn restore_prompts(
+    env: &RwLock<Environment<'_>>,
+    queue: &Mutex<Vec<GenerateContext>>,
+    path: &Path,
+    model: &str,
+) -> Result<()> {
+    let prefixes = shutdown::cached_tokens(path, model)?;
+    let mut queue = queue.lock().await;
+    let env = env.read().await;
+    warm_tokens(&env, &mut queue, prefixes).await
+}
+
+/// Queue the prompts so that their states are computed and cached.
+async fn warm_prompts(
+    env: &Environment<'_>,
+    queue: &mut Vec<GenerateContext>,
+    prompts: Vec<String>,
+) -> Result<()> {
+    let Environment::Loaded { runtime, .. } = env else {
+        bail!("model not loaded")
+    };
+    let tokenizer = runtime.tokenizer();
+    let prompts = prompts
+        .iter()
+        .map(|prompt| tokenizer.encode(prompt.as_bytes()))
+        .try_collect()?;
+    warm_tokens(env, queue, prompts).await
+}
+
+/// Queue the tokenized prompts so that their states are computed and cached.
+async fn warm_tokens(
+    env: &Environment<'_>,
+    queue: &mut Vec<GenerateContext>,
+    prompts: Vec<Vec<u16>>,
+) -> Result<()> {
+    let Environment::Loaded { .. } = env else {
+        bail!("model not loaded")
+    };
+    for tokens in prompts {
+        let context = GenerateContext::warm_up(tokens, Span::current());
+        queue.append(&mut env.enqueue(context).await);
+    }
+    Ok(())
+}
+
 fn load_web(path: impl AsRef<Path>, target: &Path) -> Result<()> {
     let file = File::open(path)?;
     let map = unsafe { Mmap::map(&file)? };
@@ -317,15 +697,66 @@ fn load_config(path: impl AsRef<Path>) -> Result<Config> {
     Ok(toml::from_str(&contents)?)
 }
 
+/// How often the cache metrics of each model are published.
+const CACHE_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time a client rejected from the queue is suggested to wait before retrying: until the oldest request leaves
+/// the queue, which it does at the latest when it expires, or else roughly a second for each request in the queue.
+fn retry_after(queue: &[GenerateContext], max_wait: Duration) -> Duration {
+    let retry = match max_wait.is_zero() {
+        true => Duration::from_secs(queue.len() as u64),
+        false => {
+            let now = Instant::now();
+            queue
+                .iter()
+                .map(|context| (context.time + max_wait).saturating_duration_since(now))
+                .filter(|remain| !remain.is_zero())
+                .min()
+                .unwrap_or_default()
+        }
+    };
+    retry.max(Duration::from_secs(1))
+}
+
+/// Drop the queued requests whose clients are gone, and reject those that have waited longer than `max_wait`.
+/// Warm-up contexts are kept until they run, since nothing waits on them.
+fn retain_queued(queue: &mut Vec<GenerateContext>, max_wait: Duration) {
+    let retry = retry_after(queue, max_wait);
+    queue.retain(|context| {
+        let expired = !context.warm_up && !max_wait.is_zero() && context.time.elapsed() >= max_wait;
+        if expired {
+            tracing::warn!(parent: &context.span, "waited too long, request rejected");
+            let _ = context
+                .sender
+                .send(Token::Reject(QueueError::Timeout(retry)));
+        }
+        !expired && !context.abandoned()
+    });
+}
+
 #[tokio::main]
-async fn model_route(receiver: Receiver<ThreadRequest>, setting: Setting) -> Result<()> {
+async fn model_route(
+    receiver: Receiver<ThreadRequest>,
+    setting: Setting,
+    model: String,
+    in_flight: Arc<AtomicUsize>,
+) -> Result<()> {
     let env: Arc<RwLock<Environment>> = Default::default();
     let queue: Arc<Mutex<Vec<GenerateContext>>> = Default::default();
+    // signaled when pending contexts may be queued, i.e., a slot is freed or a new request comes
+    let notify: Arc<Notify> = Default::default();
+    let pulse: Arc<RwLock<Pulse>> = Default::default();
+    // set on shutdown, after which no request is admitted
+    let closing: Arc<AtomicBool> = Default::default();
 
     let sender = {
         let (sender, receiver) = flume::unbounded();
         let env = env.clone();
-        tokio::task::spawn_blocking(move || run::run(receiver, env, setting));
+        let setting = setting.clone();
+        let notify = notify.clone();
+        let model = model.clone();
+        let pulse = pulse.clone();
+        tokio::task::spawn_blocking(move || run::run(receiver, env, setting, notify, model, pulse));
         sender
     };
 
@@ -333,45 +764,136 @@ async fn model_route(receiver: Receiver<ThreadRequest>, setting: Setting) -> Res
         let env = env.clone();
         let queue = queue.clone();
         let sender = sender.clone();
+        let notify = notify.clone();
+        let model = model.clone();
+        let closing = closing.clone();
+        let max_wait = Duration::from_secs(setting.max_queue_wait);
 
         async move {
             loop {
+                // also wake up when the earliest pending request is due
+                let deadline = match max_wait.is_zero() {
+                    true => None,
+                    false => queue.lock().await.iter().map(|x| x.time + max_wait).min(),
+                };
+                match deadline {
+                    Some(deadline) => {
+                        let deadline = tokio::time::Instant::from_std(deadline);
+                        let _ = tokio::time::timeout_at(deadline, notify.notified()).await;
+                    }
+                    None => notify.notified().await,
+                }
+
                 let mut queue = queue.lock().await;
+                if closing.load(Ordering::Acquire) {
+                    for context in queue.drain(..) {
+                        tracing::warn!(parent: &context.span, "shutting down, request rejected");
+                        let _ = context.sender.send(Token::Reject(QueueError::Shutdown));
+                    }
+                    metric::queue_depth(&model, 0);
+                    continue;
+                }
+
+                // hand pending contexts to the runtime in a fair order
+                let order = run::schedule(&queue.iter().collect_vec(), &[]);
+                let mut pending = std::mem::take(&mut *queue)
+                    .into_iter()
+                    .map(Some)
+                    .collect_vec();
                 let mut temp = vec![];
-                for context in queue.drain(..) {
+                for context in order.into_iter().filter_map(|index| pending[index].take()) {
                     temp.append(&mut env.read().await.enqueue(context).await);
                     let _ = sender.send(());
                 }
-                std::mem::swap(&mut *queue, &mut temp);
-                drop(queue);
 
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                // drop requests that have waited too long, and report positions to the rest
+                retain_queued(&mut temp, max_wait);
+                metric::queue_depth(&model, temp.len());
+                for (position, context) in temp.iter().enumerate() {
+                    let _ = context.sender.send(Token::Queue(position));
+                }
+                std::mem::swap(&mut *queue, &mut temp);
             }
         }
     };
     tokio::spawn(dequeue);
 
+    // publish the cache metrics on a timer instead of locking the cache in the inference loop
+    let metrics = {
+        let env = env.clone();
+        let model = model.clone();
+        async move {
+            let mut interval = tokio::time::interval(CACHE_METRICS_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Environment::Loaded { runtime, .. } = &*env.read().await {
+                    metric::cache(&model, &runtime.cache_stats().await);
+                }
+            }
+        }
+    };
+    tokio::spawn(metrics);
+
     loop {
         let listen = async {
             match receiver.recv_async().await.unwrap() {
-                ThreadRequest::Adapter(sender) => {
+                ThreadRequest::Adapter(_)
+                | ThreadRequest::Models(_)
+                | ThreadRequest::Demand { .. }
+                | ThreadRequest::Lora { .. } => {
+                    tracing::error!("model {model} received a request for the dispatcher");
+                }
+                ThreadRequest::Close => {
+                    closing.store(true, Ordering::Release);
+                    notify.notify_one();
+                }
+                ThreadRequest::Dump(sender) => {
+                    let env = env.clone();
+                    let model = model.clone();
                     let task = async move {
-                        let _ = sender.send(list_adapters());
+                        if let Environment::Loaded { runtime, .. } = &*env.read().await {
+                            let _ = sender.send((model, runtime.cached_tokens().await));
+                        }
                     };
                     tokio::spawn(task);
                 }
-                ThreadRequest::Info(sender) => {
+                ThreadRequest::Pulse(sender) => {
+                    let Pulse { busy, beat } = *pulse.read().await;
+                    let stalled = beat.elapsed();
+                    let timeout = Duration::from_secs(setting.live_timeout);
+                    let _ = sender.send(ModelPulse {
+                        model: model.clone(),
+                        busy,
+                        stalled: stalled.as_secs_f32(),
+                        live: !busy || timeout.is_zero() || stalled < timeout,
+                    });
+                }
+                ThreadRequest::Slots { sender, .. } => {
+                    let env = env.clone();
+                    let task = async move {
+                        if let Environment::Loaded { runtime, .. } = &*env.read().await {
+                            let _ = sender.send(runtime.slots().await);
+                        }
+                    };
+                    tokio::spawn(task);
+                }
+                ThreadRequest::Info { sender, .. } => {
                     let env = env.clone();
                     let task = async move {
                         let env = &(*env.read().await);
-                        if let Environment::Loaded { runtime, reload } = env {
-                            let reload = reload.clone();
+                        if let Environment::Loaded {
+                            runtime, reload, ..
+                        } = env
+                        {
+                            let reload = reload.as_ref().clone();
                             let model = runtime.info().clone();
                             let tokenizer = runtime.tokenizer();
+                            let cache = runtime.cache_stats().await;
                             let _ = sender.send(RuntimeInfo {
                                 reload,
                                 model,
                                 tokenizer,
+                                cache,
                             });
                         }
                     };
@@ -379,130 +901,260 @@ async fn model_route(receiver: Receiver<ThreadRequest>, setting: Setting) -> Res
                 }
                 ThreadRequest::Reload {
                     request,
+                    memory,
                     sender: reload_sender,
                 } => {
-                    let callback = move |result: bool| {
-                        if let Some(sender) = reload_sender {
-                            let _ = sender.send(result);
-                        }
-                    };
                     let sender = sender.clone();
                     let env = env.clone();
+                    let queue = queue.clone();
+                    let notify = notify.clone();
+                    let setting = setting.clone();
+                    let model = model.clone();
                     let reload = async move {
-                        let sender = sender.clone();
-                        let max_runtime_batch = request.max_runtime_batch;
-                        let embed_layer = request.embed_layer;
-
-                        let file = File::open(&request.model_path)?;
-                        let data = unsafe { Mmap::map(&file)? };
-                        let info = Loader::info(&data)?;
-                        log::info!("{:#?}", info);
-
-                        let context = create_context(request.adapter, &info).await?;
-                        let tokenizer = load_tokenizer(&request.tokenizer_path)?;
-                        log::info!("{:#?}", context.adapter.get_info());
-
-                        let mut env = env.write().await;
-                        *env = Environment::None;
-
-                        let runtime = match info.version {
-                            ModelVersion::V4 => {
-                                let (model, state) = load_model(&context, request.clone(), &data)?;
-                                RuntimeUntyped::V4(Runtime::new(
-                                    tokenizer,
-                                    model,
-                                    state,
-                                    max_runtime_batch,
-                                    embed_layer,
-                                ))
+                        let mut reporter = LoadReporter::new(reload_sender);
+                        let result = async {
+                            let sender = sender.clone();
+                            let max_runtime_batch = request.max_runtime_batch;
+                            let embed_layer = request.embed_layer;
+                            let max_cache_items = request.max_cache_items;
+                            let max_cache_memory = request.max_cache_memory;
+                            let pinned_prompts = request.pinned_prompts.clone();
+                            let cache_path = setting.cache_path.clone();
+
+                            let file = File::open(&request.model_path)?;
+                            let data = unsafe { Mmap::map(&file)? };
+                            let data = {
+                                let mut reporter = reporter.clone();
+                                tokio::task::spawn_blocking(move || {
+                                    prefetch(&data, &mut reporter);
+                                    data
+                                })
+                                .await?
+                            };
+                            let info = Loader::info(&data)?;
+                            tracing::info!("{:#?}", info);
+
+                            reporter.report(LoadPhase::Context, None);
+                            let context = create_context(request.adapter, &info).await?;
+                            reporter.report(LoadPhase::Tokenizer, None);
+                            let tokenizer = load_tokenizer(&request.tokenizer_path)?;
+                            tracing::info!("{:#?}", context.adapter.get_info());
+
+                            // the old model keeps serving while the new one is built
+                            let runtime = match info.version {
+                                ModelVersion::V4 => {
+                                    let (model, state) = load_model(
+                                        &context,
+                                        request.clone(),
+                                        &data,
+                                        &mut reporter,
+                                    )?;
+                                    RuntimeUntyped::V4(Runtime::new(
+                                        tokenizer,
+                                        model,
+                                        state,
+                                        max_runtime_batch,
+                                        embed_layer,
+                                        max_cache_items,
+                                        max_cache_memory,
+                                    ))
+                                }
+                                ModelVersion::V5 => {
+                                    let (model, state) = load_model(
+                                        &context,
+                                        request.clone(),
+                                        &data,
+                                        &mut reporter,
+                                    )?;
+                                    RuntimeUntyped::V5(Runtime::new(
+                                        tokenizer,
+                                        model,
+                                        state,
+                                        max_runtime_batch,
+                                        embed_layer,
+                                        max_cache_items,
+                                        max_cache_memory,
+                                    ))
+                                }
+                                ModelVersion::V6 => {
+                                    let (model, state) = load_model(
+                                        &context,
+                                        request.clone(),
+                                        &data,
+                                        &mut reporter,
+                                    )?;
+                                    RuntimeUntyped::V6(Runtime::new(
+                                        tokenizer,
+                                        model,
+                                        state,
+                                        max_runtime_batch,
+                                        embed_layer,
+                                        max_cache_items,
+                                        max_cache_memory,
+                                    ))
+                                }
+                            };
+                            reporter.report(LoadPhase::Probe, None);
+                            let runtime =
+                                tokio::task::spawn_blocking(move || run::probe(runtime)).await??;
+
+                            let reload = Box::new(request);
+                            let env_old = {
+                                let mut lock = env.write().await;
+                                std::mem::replace(
+                                    &mut *lock,
+                                    Environment::Loaded {
+                                        runtime,
+                                        reload,
+                                        memory,
+                                    },
+                                )
+                            };
+                            if let Environment::Loaded {
+                                runtime, memory, ..
+                            } = env_old
+                            {
+                                tokio::task::spawn_blocking(move || {
+                                    run::drain(runtime, setting);
+                                    drop(memory);
+                                });
                             }
-                            ModelVersion::V5 => {
-                                let (model, state) = load_model(&context, request.clone(), &data)?;
-                                RuntimeUntyped::V5(Runtime::new(
-                                    tokenizer,
-                                    model,
-                                    state,
-                                    max_runtime_batch,
-                                    embed_layer,
-                                ))
+
+                            reporter.report(LoadPhase::Pin, None);
+                            if let Err(err) = pin_prompts(&env, &queue, pinned_prompts).await {
+                                tracing::error!("failed to pin prompts: {}", err);
                             }
-                            ModelVersion::V6 => {
-                                let (model, state) = load_model(&context, request.clone(), &data)?;
-                                RuntimeUntyped::V6(Runtime::new(
-                                    tokenizer,
-                                    model,
-                                    state,
-                                    max_runtime_batch,
-                                    embed_layer,
-                                ))
+                            if !cache_path.as_os_str().is_empty() {
+                                if let Err(err) =
+                                    restore_prompts(&env, &queue, &cache_path, &model).await
+                                {
+                                    tracing::error!("failed to restore cached prompts: {}", err);
+                                }
                             }
-                        };
-                        let reload = request;
-                        *env = Environment::Loaded { runtime, reload };
+                            notify.notify_one();
 
-                        let _ = sender.send(());
-                        anyhow::Ok(())
-                    };
+                            let _ = sender.send(());
+                            anyhow::Ok(())
+                        }
+                        .await;
 
-                    let reload = async move {
-                        match reload.await {
+                        match result {
                             Ok(_) => {
-                                callback(true);
-                                log::info!("model reloaded")
-                            }
-                            Err(err) => {
-                                callback(false);
-                                log::error!("reload model failed: {}", err);
+                                reporter.report(LoadPhase::Done, None);
+                                tracing::info!("model reloaded");
                             }
+                            Err(err) => reporter.fail(&err),
                         }
                     };
                     tokio::spawn(reload);
                 }
-                ThreadRequest::Unload => {
+                ThreadRequest::Unload {
+                    sender: unload_sender,
+                    ..
+                } => {
                     let env = env.clone();
+                    let setting = setting.clone();
                     let unload = async move {
-                        let mut env = env.write().await;
-                        *env = Environment::None;
-                        log::info!("model unloaded");
+                        let env_old = std::mem::take(&mut *env.write().await);
+                        if let Environment::Loaded {
+                            runtime, memory, ..
+                        } = env_old
+                        {
+                            tokio::task::spawn_blocking(move || {
+                                run::drain(runtime, setting);
+                                drop(memory);
+                            });
+                        }
+                        tracing::info!("model unloaded");
+                        let _ = unload_sender.send(());
                     };
                     tokio::spawn(unload);
                 }
-                ThreadRequest::Generate {
+                ThreadRequest::Cache {
                     request,
-                    tokenizer,
-                    sender: token_sender,
+                    sender: cache_sender,
+                    ..
                 } => {
-                    let tokens = Tokens(tokenizer.encode(request.prompt.as_bytes())?);
-                    let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
-                    let mut penalties = HashMap::new();
-                    for (index, token) in model_tokens.iter().rev().enumerate() {
-                        let ap = request.sampler.presence_penalty;
-                        let af = request.sampler.frequency_penalty;
-                        let ad = request.sampler.penalty_decay;
-                        let mut penalty = penalties.remove(token).unwrap_or(ap);
-                        penalty += af * ad.powf(index as f32);
-                        penalties.insert(*token, penalty);
-                    }
-
-                    let context = GenerateContext {
-                        prompt_tokens: tokens.to_vec(),
-                        prefix: Default::default(),
-                        suffix: tokens,
-                        penalties,
-                        model_text: Default::default(),
-                        output_buffer: Default::default(),
-                        model_tokens: Default::default(),
-                        request,
-                        sender: token_sender,
+                    let env = env.clone();
+                    let queue = queue.clone();
+                    let sender = sender.clone();
+                    let task = async move {
+                        let response = match request {
+                            CacheRequest::Pin(prompts) => pin_prompts(&env, &queue, prompts).await,
+                            request => env.read().await.cache(request).await,
+                        };
+                        let _ = sender.send(());
+                        match response {
+                            Ok(response) => {
+                                let _ = cache_sender.send(response);
+                            }
+                            Err(err) => tracing::error!("cache request failed: {}", err),
+                        }
                     };
-
+                    tokio::spawn(task);
+                }
+                ThreadRequest::Cancel {
+                    id,
+                    sender: cancel_sender,
+                } => {
                     let env = env.clone();
                     let queue = queue.clone();
                     let sender = sender.clone();
+                    let notify = notify.clone();
                     let task = async move {
                         let mut queue = queue.lock().await;
-                        queue.append(&mut env.read().await.enqueue(context).await);
+                        let found = match queue.iter().position(|x| x.request.id == id) {
+                            Some(index) => {
+                                queue.remove(index).finish(FinishReason::Cancelled);
+                                true
+                            }
+                            None => env.read().await.cancel(&id).await,
+                        };
+                        drop(queue);
+
                         let _ = sender.send(());
+                        notify.notify_one();
+                        let _ = cancel_sender.send(found);
+                    };
+                    tokio::spawn(task);
+                }
+                ThreadRequest::Generate {
+                    request,
+                    tokenizer,
+                    sender: token_sender,
+                    span,
+                } => {
+                    let mut context =
+                        GenerateContext::new(*request, &tokenizer, token_sender, span)?;
+                    context.in_flight = Some(Arc::new(InFlight::new(in_flight.clone())));
+
+                    // always go through the queue so that earlier requests are served first
+                    let queue = queue.clone();
+                    let notify = notify.clone();
+                    let max_depth = setting.max_queue_depth;
+                    let max_wait = Duration::from_secs(setting.max_queue_wait);
+                    let closing = closing.clone();
+                    let task = async move {
+                        let mut queue = queue.lock().await;
+                        if closing.load(Ordering::Acquire) {
+                            let _ = context.sender.send(Token::Reject(QueueError::Shutdown));
+                            return;
+                        }
+                        if max_depth > 0 && queue.len() >= max_depth {
+                            tracing::warn!(parent: &context.span, "queue is full, request rejected");
+                            let retry = retry_after(&queue, max_wait);
+                            let _ = context.sender.send(Token::Reject(QueueError::Full(retry)));
+                            return;
+                        }
+                        tracing::info!(
+                            parent: &context.span,
+                            position = queue.len(),
+                            prompt_tokens = context.prompt_tokens.len(),
+                            "queued"
+                        );
+                        let _ = context.sender.send(Token::Queue(queue.len()));
+                        queue.push(context);
+                        notify.notify_one();
                     };
                     tokio::spawn(task);
                 }
@@ -511,82 +1163,740 @@ async fn model_route(receiver: Receiver<ThreadRequest>, setting: Setting) -> Res
         };
 
         if let Err(err) = listen.await {
-            log::error!("{err}");
+            tracing::error!("{err}");
         }
     }
 }
 
-pub async fn try_request_info(sender: Sender<ThreadRequest>) -> Result<RuntimeInfo> {
-    let (info_sender, info_receiver) = flume::unbounded();
-    let _ = sender.send(ThreadRequest::Info(info_sender));
-    let info = info_receiver.recv_async().await?;
-    Ok(info)
+/// How often idle models are looked for.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Budget of the GPU memory of all models, counting runtimes from when they are built until they are drained.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// Limit in bytes; `0` for no limit.
+    limit: usize,
+    held: std::sync::Mutex<usize>,
+    released: Notify,
+}
+
+/// Memory held in the budget by a runtime, released when dropped.
+#[derive(Debug)]
+pub struct HeldMemory {
+    budget: Arc<MemoryBudget>,
+    memory: usize,
 }
 
-pub async fn request_info(sender: Sender<ThreadRequest>, sleep: Duration) -> RuntimeInfo {
-    loop {
-        if let Ok(info) = try_request_info(sender.clone()).await {
-            break info;
+impl Drop for HeldMemory {
+    fn drop(&mut self) {
+        let mut held = self.budget.held.lock().unwrap();
+        *held = held.saturating_sub(self.memory);
+        self.budget.released.notify_waiters();
+    }
+}
+
+impl MemoryBudget {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            held: Default::default(),
+            released: Default::default(),
+        }
+    }
+
+    /// Wait until `memory` fits in the budget and hold it.
+    /// The memory of the `replaced` runtime may be exceeded, since it is only drained after the new one is built.
+    async fn hold(self: &Arc<Self>, memory: usize, replaced: usize) -> HeldMemory {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut held = self.held.lock().unwrap();
+                if self.limit == 0 || *held + memory <= self.limit + replaced {
+                    *held += memory;
+                    break;
+                }
+            }
+            released.await;
+        }
+        HeldMemory {
+            budget: self.clone(),
+            memory,
         }
-        tokio::time::sleep(sleep).await;
     }
 }
 
-pub async fn request_info_stream(
+/// A model that is loaded or being loaded by its worker.
+struct Resident {
+    request: ReloadRequest,
+    /// Estimated GPU memory in bytes.
+    memory: usize,
+    /// When the model is last requested.
+    used: Instant,
+    /// Progress of the loading, which clients wait on before using the model.
+    /// A model whose load failed stays with the failed progress, so that it is not loaded again on demand.
+    progress: watch::Receiver<LoadProgress>,
+    /// Reload of the model, which takes its place once done; the model keeps serving until then.
+    replacement: Option<Replacement>,
+}
+
+impl Resident {
+    /// Whether the last load of the model failed, in which case it is kept only to not be loaded again on demand.
+    fn failed(&self) -> bool {
+        self.progress.borrow().phase == LoadPhase::Failed
+    }
+}
+
+/// A model being loaded to replace a loaded one of the same name.
+struct Replacement {
+    request: ReloadRequest,
+    memory: usize,
+    progress: watch::Receiver<LoadProgress>,
+}
+
+struct Worker {
     sender: Sender<ThreadRequest>,
-    info_sender: Sender<RuntimeInfo>,
-    sleep: Duration,
-) {
-    loop {
-        if let Ok(info) = try_request_info(sender.clone()).await {
-            if info_sender.send(info).is_err() {
+    /// Number of generate requests queued or running on the model.
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Routes requests to the worker of each model, and loads or unloads models on demand.
+struct Dispatcher {
+    setting: Setting,
+    /// Models in the config and LoRA variants by name; the empty name is for the default model.
+    catalogue: HashMap<String, ReloadRequest>,
+    workers: HashMap<String, Worker>,
+    residents: Arc<Mutex<HashMap<String, Resident>>>,
+    budget: Arc<MemoryBudget>,
+    /// Models unloaded through the API, which are not loaded on demand until they are loaded explicitly.
+    unloaded: HashSet<String>,
+}
+
+impl Dispatcher {
+    fn worker(&mut self, name: &str) -> Sender<ThreadRequest> {
+        let setting = &self.setting;
+        self.workers
+            .entry(name.into())
+            .or_insert_with(|| {
+                let (sender, receiver) = flume::unbounded();
+                let in_flight: Arc<AtomicUsize> = Default::default();
+                let setting = setting.clone();
+                let model = name.to_owned();
+                let count = in_flight.clone();
+                tokio::task::spawn_blocking(move || model_route(receiver, setting, model, count));
+                Worker { sender, in_flight }
+            })
+            .sender
+            .clone()
+    }
+
+    /// Whether the model has requests queued or running, in which case it is not unloaded to free memory.
+    fn busy(&self, name: &str) -> bool {
+        self.workers
+            .get(name)
+            .is_some_and(|worker| worker.in_flight.load(Ordering::Acquire) > 0)
+    }
+
+    /// Name of the model serving requests for `model`, given either by its name or by the id shown to clients.
+    /// Returns `None` if no model matches.
+    async fn resolve(&self, model: &str) -> Option<String> {
+        let residents = self.residents.lock().await;
+        if residents.contains_key(model) || self.catalogue.contains_key(model) {
+            return Some(model.into());
+        }
+        residents
+            .iter()
+            .map(|(name, resident)| (name, &resident.request))
+            .chain(self.catalogue.iter())
+            .find(|(_, request)| request.model_id() == model)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Mark the model as used, and load it if it is in the config but not loaded.
+    /// Returns the progress of its loading, or an error if it is unloaded and not to be loaded.
+    /// A model whose last load failed is not loaded again until it is reloaded explicitly.
+    async fn demand(&mut self, name: &str) -> Result<watch::Receiver<LoadProgress>, ApiError> {
+        if let Some(resident) = self.residents.lock().await.get_mut(name) {
+            resident.used = Instant::now();
+            return Ok(resident.progress.clone());
+        }
+        if self.unloaded.contains(name) {
+            return Err(ApiError::ModelNotLoaded);
+        }
+        if let Some(request) = self.catalogue.get(name).cloned() {
+            tracing::info!("loading model {} on demand", request.model_id());
+            self.load(request, None).await;
+        }
+        match self.residents.lock().await.get(name) {
+            Some(resident) => Ok(resident.progress.clone()),
+            None => Err(ApiError::ModelNotLoaded),
+        }
+    }
+
+    async fn load(&mut self, request: ReloadRequest, sender: Option<Sender<LoadProgress>>) {
+        let name = request.name.clone();
+        let memory = match memory::estimate(&request) {
+            Ok(estimate) => estimate.total,
+            Err(err) => {
+                tracing::warn!(
+                    "failed to estimate memory of {}: {}",
+                    request.model_id(),
+                    err
+                );
+                0
+            }
+        };
+
+        let residents = self.residents.clone();
+        let mut residents = residents.lock().await;
+        // a loaded model keeps serving while it is reloaded, and its memory is replaced by the new one
+        let serving = residents
+            .get(&name)
+            .is_some_and(|resident| resident.progress.borrow().phase == LoadPhase::Done);
+        let replaced = match serving {
+            true => residents[&name].memory,
+            false => residents
+                .remove(&name)
+                .map(|resident| resident.memory)
+                .unwrap_or_default(),
+        };
+
+        // unload least recently used models until the new one fits, sparing those in use;
+        // their memory is held until they are drained, which the load waits for
+        let budget = self.budget.limit;
+        let total = |residents: &HashMap<String, Resident>| -> usize {
+            residents
+                .iter()
+                .filter(|(other, _)| **other != name)
+                .map(|(_, resident)| resident.memory)
+                .sum()
+        };
+        while budget > 0 && total(&residents) + memory > budget {
+            let Some(lru) = residents
+                .iter()
+                .filter(|(other, resident)| {
+                    **other != name && !resident.failed() && !self.busy(other)
+                })
+                .min_by_key(|(_, resident)| resident.used)
+                .map(|(name, _)| name.clone())
+            else {
                 break;
+            };
+            tracing::info!(
+                "unloading model {} to free memory",
+                residents[&lru].request.model_id()
+            );
+            residents.remove(&lru);
+            self.unload(&lru, None);
+        }
+        if budget > 0 && total(&residents) + memory > budget {
+            let error = match memory > budget {
+                true => format!("model {} exceeds the memory budget", request.model_id()),
+                false => format!(
+                    "model {} does not fit in the memory budget beside the models in use",
+                    request.model_id()
+                ),
+            };
+            tracing::error!("{error}");
+            metric::model_load(&name, "failed");
+            if let Some(sender) = sender {
+                let _ = sender.send(LoadProgress {
+                    phase: LoadPhase::Failed,
+                    percent: None,
+                    error: Some(error),
+                });
+            }
+            return;
+        }
+
+        let (watch_sender, watch_receiver) = watch::channel(LoadProgress {
+            phase: LoadPhase::Read,
+            percent: None,
+            error: None,
+        });
+        match residents.get_mut(&name) {
+            Some(resident) if serving => {
+                resident.replacement = Some(Replacement {
+                    request: request.clone(),
+                    memory,
+                    progress: watch_receiver.clone(),
+                });
+            }
+            _ => {
+                let resident = Resident {
+                    request: request.clone(),
+                    memory,
+                    used: Instant::now(),
+                    progress: watch_receiver.clone(),
+                    replacement: None,
+                };
+                residents.insert(name.clone(), resident);
+            }
+        }
+        drop(residents);
+
+        // a replacement takes the place of the model once done, and is dropped if it fails;
+        // a model that fails to load keeps its failed progress and frees its memory in the budget
+        let (progress_sender, progress_receiver) = flume::unbounded::<LoadProgress>();
+        let residents = self.residents.clone();
+        let task = {
+            let name = name.clone();
+            async move {
+                while let Ok(progress) = progress_receiver.recv_async().await {
+                    let done = match progress.phase {
+                        LoadPhase::Done => {
+                            metric::model_load(&name, "done");
+                            Some(true)
+                        }
+                        LoadPhase::Failed => {
+                            metric::model_load(&name, "failed");
+                            Some(false)
+                        }
+                        _ => None,
+                    };
+                    if let Some(done) = done {
+                        let mut residents = residents.lock().await;
+                        if let Some(resident) = residents.get_mut(&name) {
+                            let replacing = resident
+                                .replacement
+                                .as_ref()
+                                .is_some_and(|x| x.progress.same_channel(&watch_receiver));
+                            if replacing {
+                                let replacement = resident.replacement.take();
+                                if let Some(replacement) = replacement.filter(|_| done) {
+                                    resident.request = replacement.request;
+                                    resident.memory = replacement.memory;
+                                    resident.progress = replacement.progress;
+                                }
+                            } else if !done && resident.progress.same_channel(&watch_receiver) {
+                                resident.memory = 0;
+                            }
+                        }
+                    }
+                    watch_sender.send_replace(progress.clone());
+                    if let Some(sender) = &sender {
+                        let _ = sender.send(progress);
+                    }
+                }
+            }
+        };
+        tokio::spawn(task);
+
+        // wait for the memory freed by draining models before building the new one
+        let worker = self.worker(&name);
+        let budget = self.budget.clone();
+        let task = async move {
+            let memory = budget.hold(memory, replaced).await;
+            let _ = worker.send(ThreadRequest::Reload {
+                request,
+                memory: Some(Arc::new(memory)),
+                sender: Some(progress_sender),
+            });
+        };
+        tokio::spawn(task);
+    }
+
+    fn unload(&self, name: &str, sender: Option<Sender<()>>) {
+        let sender = sender.unwrap_or_else(|| flume::unbounded().0);
+        if let Some(worker) = self.workers.get(name) {
+            metric::model_unload(name);
+            let _ = worker.sender.send(ThreadRequest::Unload {
+                model: name.into(),
+                sender,
+            });
+        }
+    }
+
+    async fn unload_idle(&mut self) {
+        let timeout = Duration::from_secs(self.setting.model_idle_timeout);
+        if timeout.is_zero() {
+            return;
+        }
+        let mut residents = self.residents.lock().await;
+        // a model is used as long as it runs requests
+        for (name, resident) in residents.iter_mut() {
+            if self.busy(name) {
+                resident.used = Instant::now();
+            }
+        }
+        let idle = residents
+            .iter()
+            .filter(|(_, resident)| !resident.failed() && resident.used.elapsed() >= timeout)
+            .map(|(name, _)| name.clone())
+            .collect_vec();
+        for name in idle {
+            if let Some(resident) = residents.remove(&name) {
+                tracing::info!("unloading idle model {}", resident.request.model_id());
+                self.unload(&name, None);
+            }
+        }
+    }
+
+    /// Register a variant of a loaded or configured model with additional LoRA.
+    /// A loaded variant of the same name is reloaded; returns `false` if the base model is unknown.
+    async fn register(&mut self, variant: LoraVariant) -> bool {
+        let base = match self.residents.lock().await.get(&variant.model) {
+            Some(resident) => Some(resident.request.clone()),
+            None => self.catalogue.get(&variant.model).cloned(),
+        };
+        let Some(base) = base else {
+            return false;
+        };
+
+        let LoraVariant {
+            name,
+            lora,
+            preload,
+            ..
+        } = variant;
+        tracing::info!("registering LoRA variant {} of {}", name, base.model_id());
+        let request = ReloadRequest {
+            name: name.clone(),
+            lora: base.lora.iter().cloned().chain(lora).collect(),
+            ..base
+        };
+        self.catalogue.insert(name.clone(), request.clone());
+
+        let loaded = self.residents.lock().await.contains_key(&name);
+        if preload {
+            self.unloaded.remove(&name);
+        }
+        if preload || loaded {
+            self.load(request, None).await;
+        }
+        true
+    }
+
+    /// List the models in the config, together with those loaded through the API.
+    async fn models(&self) -> Vec<ReloadRequest> {
+        let residents = self.residents.lock().await;
+        let mut models = self.catalogue.clone();
+        for (name, resident) in residents.iter() {
+            models.insert(name.clone(), resident.request.clone());
+        }
+        models
+            .into_values()
+            .sorted_by(|x, y| x.name.cmp(&y.name))
+            .collect()
+    }
+
+    async fn dispatch(&mut self, request: ThreadRequest) {
+        match request {
+            ThreadRequest::Adapter(sender) => {
+                let task = async move {
+                    let _ = sender.send(list_adapters());
+                };
+                tokio::spawn(task);
+            }
+            ThreadRequest::Models(sender) => {
+                let _ = sender.send(self.models().await);
+            }
+            ThreadRequest::Demand { model, sender } => {
+                let progress = match self.resolve(&model).await {
+                    Some(name) => self.demand(&name).await,
+                    None => Err(ApiError::ModelNotFound(model)),
+                };
+                let _ = sender.send(progress);
+            }
+            ThreadRequest::Info { model, sender } => {
+                let Some(name) = self.resolve(&model).await else {
+                    return;
+                };
+                if let Some(worker) = self.workers.get(&name) {
+                    let _ = worker.sender.send(ThreadRequest::Info { model, sender });
+                }
+            }
+            ThreadRequest::Generate {
+                request,
+                tokenizer,
+                sender,
+                span,
+            } => {
+                let Some(name) = self.resolve(&request.model).await else {
+                    return;
+                };
+                if self.demand(&name).await.is_err() {
+                    return;
+                }
+                if let Some(worker) = self.workers.get(&name) {
+                    let _ = worker.sender.send(ThreadRequest::Generate {
+                        request,
+                        tokenizer,
+                        sender,
+                        span,
+                    });
+                }
+            }
+            ThreadRequest::Reload {
+                request, sender, ..
+            } => {
+                self.unloaded.remove(&request.name);
+                self.load(request, sender).await;
+            }
+            ThreadRequest::Unload { model, sender } => {
+                let Some(name) = self.resolve(&model).await else {
+                    return;
+                };
+                self.residents.lock().await.remove(&name);
+                self.unloaded.insert(name.clone());
+                self.unload(&name, Some(sender));
+            }
+            ThreadRequest::Lora { variant, sender } => {
+                let _ = sender.send(self.register(variant).await);
+            }
+            ThreadRequest::Cancel { id, sender } => {
+                let workers = self
+                    .workers
+                    .values()
+                    .map(|worker| worker.sender.clone())
+                    .collect_vec();
+                let task = async move {
+                    let mut found = false;
+                    for worker in workers {
+                        let (cancel_sender, cancel_receiver) = flume::unbounded();
+                        let _ = worker.send(ThreadRequest::Cancel {
+                            id: id.clone(),
+                            sender: cancel_sender,
+                        });
+                        found |= cancel_receiver.recv_async().await.unwrap_or_default();
+                    }
+                    let _ = sender.send(found);
+                };
+                tokio::spawn(task);
+            }
+            ThreadRequest::Pulse(sender) => {
+                for worker in self.workers.values() {
+                    let _ = worker.sender.send(ThreadRequest::Pulse(sender.clone()));
+                }
+            }
+            ThreadRequest::Close => {
+                for worker in self.workers.values() {
+                    let _ = worker.sender.send(ThreadRequest::Close);
+                }
+            }
+            ThreadRequest::Dump(sender) => {
+                for worker in self.workers.values() {
+                    let _ = worker.sender.send(ThreadRequest::Dump(sender.clone()));
+                }
+            }
+            ThreadRequest::Slots { model, sender } => {
+                let Some(name) = self.resolve(&model).await else {
+                    return;
+                };
+                if let Some(worker) = self.workers.get(&name) {
+                    let _ = worker.sender.send(ThreadRequest::Slots { model, sender });
+                }
+            }
+            ThreadRequest::Cache {
+                model,
+                request,
+                sender,
+            } => {
+                let Some(name) = self.resolve(&model).await else {
+                    return;
+                };
+                if let Some(worker) = self.workers.get(&name) {
+                    let _ = worker.sender.send(ThreadRequest::Cache {
+                        model,
+                        request,
+                        sender,
+                    });
+                }
             }
         }
-        tokio::time::sleep(sleep).await;
+    }
+}
+
+/// Serve requests of all models in the catalogue, loading those not on demand at start.
+#[tokio::main]
+async fn dispatch(receiver: Receiver<ThreadRequest>, setting: Setting, catalogue: Vec<NamedModel>) {
+    let preload = catalogue
+        .iter()
+        .filter(|model| !model.on_demand)
+        .map(|model| model.name.clone())
+        .collect_vec();
+    let catalogue = catalogue
+        .into_iter()
+        .map(|model| (model.name.clone(), ReloadRequest::from(model)))
+        .collect();
+    let mut dispatcher = Dispatcher {
+        budget: Arc::new(MemoryBudget::new(setting.max_model_memory << 20)),
+        setting,
+        catalogue,
+        workers: HashMap::new(),
+        residents: Default::default(),
+        unloaded: HashSet::new(),
+    };
+    for name in preload {
+        let _ = dispatcher.demand(&name).await;
+    }
+
+    let mut checked = Instant::now();
+    loop {
+        match tokio::time::timeout(IDLE_CHECK_INTERVAL, receiver.recv_async()).await {
+            Ok(Ok(request)) => dispatcher.dispatch(request).await,
+            Ok(Err(_)) => break,
+            Err(_) => {}
+        }
+        if checked.elapsed() >= IDLE_CHECK_INTERVAL {
+            dispatcher.unload_idle().await;
+            checked = Instant::now();
+        }
     }
 }
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Options given on the command line take precedence over their environment variables, \
+        which take precedence over the config file."
+)]
 struct Args {
-    #[arg(long, short, value_name = "FILE")]
+    #[arg(long, short, env = "AI00_CONFIG", value_name = "FILE")]
     config: Option<PathBuf>,
-    #[arg(long, short)]
+    /// IP to listen on, with `--port` or its default.
+    #[arg(long, short, env = "AI00_IP")]
     ip: Option<IpAddr>,
-    #[arg(long, short, default_value_t = 65530)]
-    port: u16,
+    /// Port to listen on, with `--ip` or all interfaces [default: 65530].
+    #[arg(long, short, env = "AI00_PORT")]
+    port: Option<u16>,
+    /// Address to listen on, either `IP:PORT` or `unix:PATH`; may be repeated.
+    /// Addresses given by `--listen`, `--ip` and `--port` replace those in the config.
+    #[arg(long, env = "AI00_LISTEN", value_name = "ADDR", value_delimiter = ',')]
+    listen: Vec<Listen>,
+    /// Permission bits in octal of the Unix domain sockets given by `--listen`, e.g., `660`.
+    #[arg(long, env = "AI00_SOCKET_MODE", value_name = "MODE", value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+    /// Serve HTTPS, with the certificate and key in the config unless given below.
+    #[arg(long, env = "AI00_TLS")]
+    tls: bool,
+    #[arg(long, env = "AI00_CERT", value_name = "FILE")]
+    cert: Option<PathBuf>,
+    #[arg(long, env = "AI00_KEY", value_name = "FILE")]
+    key: Option<PathBuf>,
+    /// Port on which plain HTTP requests are redirected to HTTPS.
+    #[arg(long, env = "AI00_REDIRECT_PORT", value_name = "PORT")]
+    redirect_port: Option<u16>,
+    #[command(flatten)]
+    overrides: overrides::Overrides,
+    /// Estimate GPU memory of the models in the config and exit, checking against the available memory in MiB if given.
+    #[arg(long, value_name = "MiB", num_args = 0..=1, default_missing_value = "0")]
+    estimate: Option<usize>,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
 }
 
+/// Port listened on if neither the arguments nor the config give an address.
+const DEFAULT_PORT: u16 = 65530;
+
 #[tokio::main]
 async fn main() {
-    simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Warn)
-        .with_module_level("ai00_server", log::LevelFilter::Trace)
-        .init()
-        .unwrap();
-
     let args = Args::parse();
     let (sender, receiver) = flume::unbounded::<ThreadRequest>();
 
-    let (setting, request) = {
+    let (setting, catalogue, listeners, tls, keys) = {
         let path = args
             .config
             .clone()
             .unwrap_or("assets/configs/Config.toml".into());
-        log::info!("reading config {}...", path.to_string_lossy());
-        let config = load_config(path).expect("load config failed");
+        let mut config = load_config(&path).expect("load config failed");
+        args.overrides.clone().apply(&mut config);
+        trace::init(&config.log).expect("init logger failed");
+        tracing::info!("read config {}", path.to_string_lossy());
 
         let setting = config.setting.clone();
-        let request = config.into();
-        (setting, request)
+        // the default model is skipped if not set, while other models must be named
+        let catalogue = config
+            .catalogue()
+            .into_iter()
+            .enumerate()
+            .filter(|(index, model)| match (index, model.name.is_empty()) {
+                (0, _) => !model.model.path.as_os_str().is_empty(),
+                (_, true) => {
+                    tracing::error!("model {} has no name", model.model.path.to_string_lossy());
+                    false
+                }
+                (_, false) => true,
+            })
+            .map(|(_, model)| model)
+            .collect_vec();
+
+        let tls = Tls {
+            enable: config.tls.enable || args.tls,
+            cert: args.cert.clone().unwrap_or(config.tls.cert),
+            key: args.key.clone().unwrap_or(config.tls.key),
+            redirect_port: args.redirect_port.unwrap_or(config.tls.redirect_port),
+        };
+
+        let keys: HashMap<_, _> = config
+            .keys
+            .into_iter()
+            .map(|key| (key.key.clone(), key))
+            .collect();
+        if keys.is_empty() {
+            tracing::warn!("no API key is configured, the API is open to everyone");
+        }
+        let mut listeners = args
+            .listen
+            .iter()
+            .cloned()
+            .map(|listen| match listen {
+                Listen::Unix { path, mode } => Listen::Unix {
+                    path,
+                    mode: mode.or(args.socket_mode),
+                },
+                listen => listen,
+            })
+            .collect_vec();
+        if args.ip.is_some() || args.port.is_some() {
+            let ip = args.ip.unwrap_or(IpAddr::from(Ipv4Addr::UNSPECIFIED));
+            let addr = SocketAddr::new(ip, args.port.unwrap_or(DEFAULT_PORT));
+            listeners.push(Listen::Tcp { addr });
+        }
+        if listeners.is_empty() {
+            listeners = config.listen;
+        }
+        if listeners.is_empty() {
+            let addr = SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), DEFAULT_PORT);
+            listeners.push(Listen::Tcp { addr });
+        }
+
+        (setting, catalogue, listeners, tls, Arc::new(keys))
     };
 
-    tokio::task::spawn_blocking(move || model_route(receiver, setting));
-    let _ = sender.send(ThreadRequest::Reload {
-        request,
-        sender: None,
-    });
+    if let Some(available) = args.estimate {
+        for model in catalogue {
+            let request = ReloadRequest::from(model);
+            let fit = match memory::estimate(&request) {
+                Ok(estimate) => match select_adapter(request.adapter).await {
+                    Ok(adapter) => memory::check(estimate, &adapter, available << 20),
+                    Err(err) => {
+                        tracing::error!("failed to select adapter: {}", err);
+                        return;
+                    }
+                },
+                Err(err) => {
+                    tracing::error!("failed to estimate {}: {}", request.model_id(), err);
+                    continue;
+                }
+            };
+            let fit = serde_json::to_string_pretty(&fit).expect("serialize estimate failed");
+            println!("{}: {}", request.model_id(), fit);
+        }
+        return;
+    }
+
+    let prometheus = metric::install().expect("install metrics recorder failed");
+    let limiter = limit::Limiter::new(setting.quota_path.clone());
+    let usage_log = usage::UsageLog::new(setting.usage_path.clone());
+    let (closing_sender, closing) = shutdown::Closing::channel();
+    let shutdown_timeout = Duration::from_secs(setting.shutdown_timeout);
+    let cache_path = setting.cache_path.clone();
+    tokio::task::spawn_blocking(move || dispatch(receiver, setting, catalogue));
 
     let serve_path = {
         let path = tempfile::tempdir()
@@ -616,43 +1926,247 @@ async fn main() {
                     .to_string_lossy()
                     .into();
                 match load_plugin(x.path(), &serve_path, &name) {
-                    Ok(_) => log::info!("loaded plugin {}", name),
-                    Err(err) => log::error!("failed to load plugin {}, {}", name, err),
+                    Ok(_) => tracing::info!("loaded plugin {}", name),
+                    Err(err) => tracing::error!("failed to load plugin {}, {}", name, err),
                 }
             }),
         Err(err) => {
-            log::error!("failed to read plugin directory: {}", err);
+            tracing::error!("failed to read plugin directory: {}", err);
         }
     };
 
-    let app = Router::new()
-        .route("/api/adapters", get(api::adapters))
-        .route("/api/files/unzip", post(api::unzip))
-        .route("/api/files/dir", post(api::dir))
-        .route("/api/files/ls", post(api::dir))
-        .route("/api/files/config/load", post(api::load_config))
-        .route("/api/files/config/save", post(api::save_config))
-        .route("/api/models/list", get(api::models))
-        .route("/api/models/info", get(api::info))
-        .route("/api/models/state", get(api::state))
-        .route("/api/models/load", post(api::load))
-        .route("/api/models/unload", get(api::unload))
+    let auth = |scope| {
+        middleware::from_fn_with_state(auth::Auth::new(keys.clone(), scope), auth::authorize)
+    };
+    let inference = Router::new()
+        .route("/api/requests/:id", delete(api::cancel))
         .route("/api/oai/models", get(oai::models))
         .route("/api/oai/v1/models", get(oai::models))
+        .route_layer(auth(Scope::Inference));
+    let generate = Router::new()
         .route("/api/oai/completions", post(oai::completions))
         .route("/api/oai/v1/completions", post(oai::completions))
         .route("/api/oai/chat/completions", post(oai::chat_completions))
         .route("/api/oai/v1/chat/completions", post(oai::chat_completions))
         .route("/api/oai/embeddings", post(oai::embeddings))
         .route("/api/oai/v1/embeddings", post(oai::embeddings))
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            limit::limit,
+        ))
+        .route_layer(auth(Scope::Inference));
+    let model = Router::new()
+        .route("/api/adapters", get(api::adapters))
+        .route("/api/models/info", get(api::info))
+        .route("/api/models/state", get(api::state))
+        .route("/api/models/load", post(api::load))
+        .route("/api/models/load/stream", post(api::load_stream))
+        .route("/api/models/estimate", post(api::estimate))
+        .route("/api/models/unload", get(api::unload))
+        .route("/api/models/lora", post(api::lora))
+        .route("/api/cache", get(api::cache::list))
+        .route("/api/cache/evict", post(api::cache::evict))
+        .route("/api/cache/flush", post(api::cache::flush))
+        .route("/api/cache/pin", post(api::cache::pin))
+        .route("/api/cache/unpin", post(api::cache::unpin))
+        .route("/api/usage", get(api::usage))
+        .route("/api/slots", get(api::slots))
+        .route("/api/slots/stream", get(api::slots_stream))
+        .route_layer(auth(Scope::Model));
+    let file = Router::new()
+        .route("/api/files/unzip", post(api::unzip))
+        .route("/api/files/dir", post(api::dir))
+        .route("/api/files/ls", post(api::dir))
+        .route("/api/files/config/load", post(api::load_config))
+        .route("/api/files/config/save", post(api::save_config))
+        .route("/api/models/list", get(api::models))
+        .route_layer(auth(Scope::File));
+    let app = Router::new()
+        .merge(inference)
+        .merge(generate)
+        .merge(model)
+        .merge(file)
+        .route(
+            "/metrics",
+            get(move || std::future::ready(prometheus.render())),
+        )
+        .route("/health", get(api::health))
+        .route("/ready", get(api::ready))
+        .route("/live", get(api::live))
         .fallback_service(ServeDir::new(serve_path))
+        .layer(middleware::from_fn(metric::track))
+        .layer(middleware::from_fn(trace::request))
+        .layer(Extension(usage_log.clone()))
+        .layer(Extension(closing))
         .layer(CorsLayer::permissive())
-        .with_state(ThreadState(sender));
-    let addr = SocketAddr::new(
-        args.ip.unwrap_or(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
-        args.port,
-    );
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    log::info!("server started at {addr}");
-    axum::serve(listener, app).await.unwrap();
+        .with_state(ThreadState(sender.clone()));
+    let signal = shutdown::signal().shared();
+    // end the endless event streams on shutdown, or their connections are never drained
+    let task = {
+        let signal = signal.clone();
+        async move {
+            signal.await;
+            let _ = closing_sender.send(true);
+        }
+    };
+    tokio::spawn(task);
+    let rustls = match tls.enable {
+        true => Some(tls::load(&tls).await.expect("load certificate failed")),
+        false => None,
+    };
+    // plain HTTP is redirected to the first TCP address served with HTTPS
+    let https = listeners.iter().find_map(|listen| match listen {
+        Listen::Tcp { addr } => Some(*addr),
+        Listen::Unix { .. } => None,
+    });
+    if let (true, Some(addr)) = (tls.enable && tls.redirect_port != 0, https) {
+        let redirect = SocketAddr::new(addr.ip(), tls.redirect_port);
+        let port = addr.port();
+        let task = async move {
+            if let Err(err) = tls::redirect(redirect, port).await {
+                tracing::error!("failed to redirect http: {}", err);
+            }
+        };
+        tokio::spawn(task);
+    }
+
+    let mut servers = vec![];
+    for listen in listeners {
+        let server = listen::serve(listen.clone(), app.clone(), rustls.clone(), signal.clone());
+        match server.await {
+            Ok(server) => servers.push(server),
+            Err(err) => {
+                tracing::error!("failed to listen on {:?}: {}", listen, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut server = futures_util::future::select_all(servers);
+    tokio::select! {
+        _ = signal => {}
+        (result, _, _) = &mut server => {
+            match result {
+                Ok(Err(err)) => tracing::error!("server failed: {}", err),
+                Ok(Ok(())) | Err(_) => tracing::error!("server stopped unexpectedly"),
+            }
+            std::process::exit(1);
+        }
+    }
+
+    // stop accepting requests and reject queued ones, but let running generations finish
+    tracing::info!("shutting down");
+    let _ = sender.send(ThreadRequest::Close);
+    let server = futures_util::future::join_all(server.into_inner());
+    let drained = match shutdown_timeout.is_zero() {
+        true => Ok(server.await),
+        false => tokio::time::timeout(shutdown_timeout, server).await,
+    };
+    match drained {
+        Ok(results) => {
+            for result in results {
+                if let Ok(Err(err)) = result {
+                    tracing::error!("server failed: {}", err);
+                }
+            }
+            tracing::info!("all requests finished");
+        }
+        Err(_) => tracing::warn!("requests not finished in time are dropped"),
+    }
+
+    if !cache_path.as_os_str().is_empty() {
+        match shutdown::save_cache(&sender, &cache_path).await {
+            Ok(()) => tracing::info!("saved cached prefixes to {}", cache_path.to_string_lossy()),
+            Err(err) => tracing::error!("failed to save cached prefixes: {}", err),
+        }
+    }
+    if let Err(err) = limiter.persist() {
+        tracing::error!("failed to save quota usage: {}", err);
+    }
+    usage_log.flush().await;
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LayerRange, QuantRange};
+
+    fn quant_range(layers: std::ops::RangeInclusive<usize>, quant_type: Quant) -> QuantRange {
+        QuantRange {
+            layers: LayerRange(layers),
+            quant_type,
+        }
+    }
+
+    #[test]
+    fn quant_map_overlapping_ranges() {
+        let request = ReloadRequest {
+            quant: 4,
+            quant_type: Quant::Int8,
+            quant_layers: vec![
+                quant_range(2..=5, Quant::NF4),
+                quant_range(5..=6, Quant::Int8),
+                quant_range(0..=0, Quant::None),
+            ],
+            ..Default::default()
+        };
+        let map = request.quant_map(8);
+        let expected = [
+            (1, Quant::Int8),
+            (2, Quant::NF4),
+            (3, Quant::NF4),
+            (4, Quant::NF4),
+            (5, Quant::Int8),
+            (6, Quant::Int8),
+        ];
+        assert_eq!(map, HashMap::from(expected));
+    }
+
+    #[test]
+    fn quant_map_clamped_to_layers() {
+        let request = ReloadRequest {
+            quant: usize::MAX,
+            quant_type: Quant::Int8,
+            quant_layers: vec![
+                quant_range(0..=99_999_999_999, Quant::NF4),
+                quant_range(30..=usize::MAX, Quant::Int8),
+            ],
+            ..Default::default()
+        };
+        let map = request.quant_map(24);
+        assert_eq!(map.len(), 24);
+        assert!(map.values().all(|quant| *quant == Quant::NF4));
+        assert!(request.quant_map(0).is_empty());
+    }
+
+    #[test]
+    fn restored_prefixes_wait_beyond_batch() {
+        let max_batch = 4;
+        let prefixes = (0..10u16).map(|index| vec![index; 8]).collect_vec();
+        let mut queue = prefixes
+            .into_iter()
+            .map(|tokens| GenerateContext::warm_up(tokens, Span::none()))
+            .collect_vec();
+        // the first batch takes the slots, and the rest wait in the queue long enough to expire
+        let mut queue = queue.split_off(max_batch);
+        std::thread::sleep(Duration::from_millis(2));
+        retain_queued(&mut queue, Duration::from_millis(1));
+        assert_eq!(queue.len(), 10 - max_batch);
+        assert!(queue.iter().all(|context| context.warm_up));
+    }
+
+    #[test]
+    fn abandoned_requests_leave_the_queue() {
+        let mut queue = vec![
+            GenerateContext::warm_up(vec![1, 2, 3], Span::none()),
+            GenerateContext {
+                warm_up: false,
+                ..GenerateContext::warm_up(vec![1, 2, 3], Span::none())
+            },
+        ];
+        retain_queued(&mut queue, Duration::ZERO);
+        assert_eq!(queue.len(), 1);
+        assert!(queue[0].warm_up);
+    }
 }
@@ -0,0 +1,142 @@
+use std::fs::File;
+
+use anyhow::Result;
+use memmap2::Mmap;
+use serde::Serialize;
+use web_rwkv::{
+    model::{loader::Loader, EmbedDevice, ModelInfo, ModelVersion, Quant},
+    wgpu::Adapter,
+};
+
+use crate::{cache::state_size, ReloadRequest};
+
+/// Bytes of a half precision float, in which unquantized weights are stored.
+const F16_SIZE: usize = 2;
+/// Bytes of a single precision float, in which states and buffers are stored.
+const F32_SIZE: usize = 4;
+/// Number of vectors (layer norms, time mixes, etc.) per layer, counted in units of `num_emb`.
+const VECTORS_PER_LAYER: usize = 16;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MemoryEstimate {
+    /// Bytes of model weights on GPU.
+    pub weights: usize,
+    /// Bytes of the states of all batches.
+    pub states: usize,
+    /// Bytes of buffers used during inference.
+    pub buffers: usize,
+    /// Bytes of the largest tensor, which must fit in a single GPU buffer.
+    pub largest: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryFit {
+    #[serde(flatten)]
+    pub estimate: MemoryEstimate,
+    /// Name of the adapter checked against.
+    pub adapter: String,
+    /// Whether the model is expected to fit on the adapter.
+    pub fit: bool,
+    /// Why the model is not expected to fit.
+    pub reasons: Vec<String>,
+}
+
+/// Bytes per weight for the quantization type, including the block-wise scales.
+fn weight_size(quant: Quant) -> f64 {
+    match quant {
+        Quant::None => F16_SIZE as f64,
+        // one byte per weight, plus min and max in f16 per block of 128
+        Quant::Int8 => 1.0 + 4.0 / 128.0,
+        // half a byte per weight, plus the absolute max in f16 per block of 64
+        Quant::NF4 => 0.5 + 2.0 / 64.0,
+    }
+}
+
+/// Estimate the GPU memory in bytes taken by the model of `info` loaded with the settings of `request`.
+pub fn estimate_info(info: &ModelInfo, request: &ReloadRequest) -> MemoryEstimate {
+    let ModelInfo {
+        num_layer,
+        num_emb,
+        num_hidden,
+        num_vocab,
+        ..
+    } = *info;
+
+    let att = match info.version {
+        ModelVersion::V4 => 4 * num_emb * num_emb,
+        ModelVersion::V5 | ModelVersion::V6 => 5 * num_emb * num_emb,
+    };
+    let ffn = 2 * num_emb * num_hidden + num_emb * num_emb;
+    let matrices = att + ffn;
+
+    let quant = request.quant_map(num_layer);
+    let layers: f64 = (0..num_layer)
+        .map(|layer| quant.get(&layer).copied().unwrap_or(Quant::None))
+        .map(|quant| matrices as f64 * weight_size(quant))
+        .sum();
+    let vectors = num_layer * VECTORS_PER_LAYER * num_emb * F16_SIZE;
+
+    let embed = match request.embed_device {
+        EmbedDevice::Cpu => 0,
+        EmbedDevice::Gpu => num_vocab * num_emb * F16_SIZE,
+    };
+    let head = num_vocab * num_emb * F16_SIZE;
+    let weights = layers as usize + vectors + embed + head;
+
+    let states = request.max_batch * state_size(info);
+
+    // activations of a chunk of tokens, and the output logits and probabilities of all batches
+    let tokens = request.token_chunk_size;
+    let activations = tokens * (VECTORS_PER_LAYER * num_emb + 2 * num_hidden);
+    let outputs = 2 * request.max_batch * num_vocab;
+    let buffers = (activations + outputs) * F32_SIZE;
+
+    let head_chunk = request.head_chunk_size.min(num_vocab) * num_emb * F16_SIZE;
+    let largest = [num_emb * num_hidden * F16_SIZE, head_chunk, embed]
+        .into_iter()
+        .max()
+        .unwrap_or_default();
+
+    MemoryEstimate {
+        weights,
+        states,
+        buffers,
+        largest,
+        total: weights + states + buffers,
+    }
+}
+
+/// Estimate the GPU memory in bytes taken by the model of the request.
+pub fn estimate(request: &ReloadRequest) -> Result<MemoryEstimate> {
+    let file = File::open(&request.model_path)?;
+    let data = unsafe { Mmap::map(&file)? };
+    let info = Loader::info(&data)?;
+    Ok(estimate_info(&info, request))
+}
+
+/// Check the estimate against the adapter limits, and against the available memory in bytes if not `0`.
+/// The total memory of the adapter is not queryable, so it has to be given.
+pub fn check(estimate: MemoryEstimate, adapter: &Adapter, available: usize) -> MemoryFit {
+    let limits = adapter.limits();
+    let mut reasons = vec![];
+    let max_buffer = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    if estimate.largest as u64 > max_buffer {
+        reasons.push(format!(
+            "the largest tensor takes {} bytes, exceeding the buffer limit of {} bytes",
+            estimate.largest, max_buffer
+        ));
+    }
+    if available > 0 && estimate.total > available {
+        reasons.push(format!(
+            "the model takes {} bytes, exceeding the available memory of {} bytes",
+            estimate.total, available
+        ));
+    }
+    MemoryFit {
+        estimate,
+        adapter: adapter.get_info().name,
+        fit: reasons.is_empty(),
+        reasons,
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::{cache::CacheStats, run::SlotCounts, FinishReason, TokenCounter};
+
+/// Buckets in seconds of latency histograms.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+/// Buckets of the generation speed histogram.
+const SPEED_BUCKETS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 50.0, 75.0, 100.0, 200.0];
+
+/// Install the global recorder, whose handle renders all metrics in the Prometheus text format.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets(&LATENCY_BUCKETS)?
+        .set_buckets_for_metric(
+            Matcher::Full("ai00_tokens_per_second".into()),
+            &SPEED_BUCKETS,
+        )?
+        .install_recorder()
+}
+
+/// Count requests by route and status, and observe their durations until the response headers.
+pub async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or("unmatched".into());
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("route", route), ("status", status)];
+    counter!("ai00_http_requests_total", &labels).increment(1);
+    histogram!("ai00_http_request_duration_seconds", &labels).record(start.elapsed().as_secs_f64());
+    response
+}
+
+pub fn first_token(time: Instant) {
+    histogram!("ai00_time_to_first_token_seconds").record(time.elapsed().as_secs_f64());
+}
+
+/// Record a finished generation, which is received at `time`.
+pub fn finish(time: Instant, reason: FinishReason, counter: &TokenCounter) {
+    let reason = match reason {
+        FinishReason::Stop => "stop",
+        FinishReason::Length => "length",
+        FinishReason::ContentFilter => "content_filter",
+        FinishReason::Cancelled => "cancelled",
+        FinishReason::Null => "null",
+    };
+    counter!("ai00_generations_total", "reason" => reason).increment(1);
+    counter!("ai00_prompt_tokens_total").increment(counter.prompt_tokens as u64);
+    counter!("ai00_completion_tokens_total").increment(counter.completion_tokens as u64);
+
+    let elapsed = time.elapsed().as_secs_f64();
+    if counter.completion_tokens > 0 && elapsed > 0.0 {
+        histogram!("ai00_tokens_per_second").record(counter.completion_tokens as f64 / elapsed);
+    }
+}
+
+pub fn queue_depth(model: &str, depth: usize) {
+    gauge!("ai00_queue_depth", "model" => model.to_owned()).set(depth as f64);
+}
+
+pub fn slots(model: &str, counts: SlotCounts) {
+    let SlotCounts { idle, wait, busy } = counts;
+    for (state, count) in [("idle", idle), ("wait", wait), ("busy", busy)] {
+        let labels = [("model", model.to_owned()), ("state", state.to_owned())];
+        gauge!("ai00_slots", &labels).set(count as f64);
+    }
+}
+
+/// Publish the stats of the prefix cache, which restart from zero whenever the model is reloaded.
+pub fn cache(model: &str, stats: &CacheStats) {
+    let model = model.to_owned();
+    counter!("ai00_cache_hits_total", "model" => model.clone()).absolute(stats.hits as u64);
+    counter!("ai00_cache_misses_total", "model" => model.clone()).absolute(stats.misses as u64);
+    counter!("ai00_cache_evictions_total", "model" => model.clone())
+        .absolute(stats.evictions as u64);
+    gauge!("ai00_cache_items", "model" => model.clone()).set(stats.items as f64);
+    gauge!("ai00_cache_bytes", "model" => model).set(stats.size as f64);
+}
+
+/// Count a model load finishing with `result`, which is either `done` or `failed`.
+pub fn model_load(model: &str, result: &'static str) {
+    let labels = [("model", model.to_owned()), ("result", result.to_owned())];
+    counter!("ai00_model_loads_total", &labels).increment(1);
+}
+
+pub fn model_unload(model: &str) {
+    counter!("ai00_model_unloads_total", "model" => model.to_owned()).increment(1);
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, time::Duration};
+use std::collections::HashMap;
 
 use anyhow::Result;
 use axum::{
@@ -10,10 +10,15 @@ use futures_util::{Stream, StreamExt};
 use itertools::Itertools;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use tracing::Span;
 
 use crate::{
-    sampler::Sampler, utils::request_info, Array, FinishReason, GenerateRequest, ThreadRequest,
-    ThreadState, Token, TokenCounter,
+    error::ApiError,
+    sampler::Sampler,
+    usage::Usage,
+    utils::{admit, queue_event, request_id, request_info, REQUEST_ID_HEADER},
+    Array, FinishReason, GenerateRequest, Priority, ThreadRequest, ThreadState, Token,
+    TokenCounter,
 };
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -57,6 +62,11 @@ pub struct ChatRequest {
     frequency_penalty: f32,
     penalty_decay: f32,
     logit_bias: HashMap<u16, f32>,
+    model: String,
+    /// Name of a LoRA variant to run the request on instead of `model`; an unknown name gets `model_not_found`.
+    lora: String,
+    user: String,
+    priority: Priority,
 }
 
 impl Default for ChatRequest {
@@ -73,6 +83,10 @@ impl Default for ChatRequest {
             frequency_penalty: 0.0,
             penalty_decay: 1.0,
             logit_bias: HashMap::new(),
+            model: String::new(),
+            lora: String::new(),
+            user: String::new(),
+            priority: Priority::default(),
         }
     }
 }
@@ -90,6 +104,10 @@ impl From<ChatRequest> for GenerateRequest {
             frequency_penalty,
             penalty_decay,
             logit_bias,
+            model,
+            lora,
+            user,
+            priority,
             ..
         } = value;
 
@@ -120,6 +138,12 @@ impl From<ChatRequest> for GenerateRequest {
         let stop = stop.into();
 
         Self {
+            model: match lora.is_empty() {
+                true => model,
+                false => lora,
+            },
+            client: user,
+            priority,
             prompt,
             model_text,
             max_tokens,
@@ -146,6 +170,7 @@ struct ChatChoice {
 
 #[derive(Debug, Serialize)]
 struct ChatResponse {
+    id: String,
     object: String,
     model: String,
     choices: Vec<ChatChoice>,
@@ -156,26 +181,34 @@ struct ChatResponse {
 async fn chat_completions_one(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<ChatRequest>,
-) -> Json<ChatResponse> {
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    id: String,
+    usage: Usage,
+) -> Result<Json<ChatResponse>, ApiError> {
+    let mut request = GenerateRequest {
+        id: id.clone(),
+        ..request.into()
+    };
+    let info = request_info(sender.clone(), &request.model).await?;
+    usage.tag(&mut request, &info);
+    let model_name = info.reload.model_id();
 
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = request.into();
     let _ = sender.send(ThreadRequest::Generate {
-        request,
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
+        span: Span::current(),
     });
 
     let mut token_counter = TokenCounter::default();
     let mut finish_reason = FinishReason::Null;
     let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let mut stream = admit(token_receiver).await?;
 
     while let Some(token) = stream.next().await {
         match token {
-            Token::Start => {}
+            Token::Queue(_) | Token::Start => {}
+            Token::Reject(err) => return Err(err.into()),
             Token::Token(token) => {
                 text += &token;
             }
@@ -188,7 +221,8 @@ async fn chat_completions_one(
         }
     }
 
-    Json(ChatResponse {
+    Ok(Json(ChatResponse {
+        id,
         object: "chat.completion".into(),
         model: model_name,
         choices: vec![ChatChoice {
@@ -200,7 +234,7 @@ async fn chat_completions_one(
             finish_reason,
         }],
         counter: token_counter,
-    })
+    }))
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -222,6 +256,7 @@ struct PartialChatChoice {
 
 #[derive(Debug, Serialize)]
 struct PartialChatResponse {
+    id: String,
     object: String,
     model: String,
     choices: Vec<PartialChatChoice>,
@@ -230,20 +265,27 @@ struct PartialChatResponse {
 async fn chat_completions_stream(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<ChatRequest>,
-) -> Sse<impl Stream<Item = Result<Event>>> {
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    id: String,
+    usage: Usage,
+) -> Result<Sse<impl Stream<Item = Result<Event>>>, ApiError> {
+    let mut request = GenerateRequest {
+        id: id.clone(),
+        ..request.into()
+    };
+    let info = request_info(sender.clone(), &request.model).await?;
+    usage.tag(&mut request, &info);
+    let model_name = info.reload.model_id();
 
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = request.into();
     let _ = sender.send(ThreadRequest::Generate {
-        request,
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
+        span: Span::current(),
     });
 
     let mut start_token = true;
-    let stream = token_receiver.into_stream().map(move |token| {
+    let stream = admit(token_receiver).await?.map(move |token| {
         let choice = match token {
             Token::Start => PartialChatChoice {
                 delta: PartialChatRecord::Role(Role::Assistant),
@@ -264,11 +306,14 @@ async fn chat_completions_stream(
                 finish_reason,
                 ..Default::default()
             },
+            Token::Queue(position) => return Ok(queue_event(position)),
+            Token::Reject(err) => return Ok(ApiError::from(err).into()),
             Token::Done => return Ok(Event::default().data("[DONE]")),
             _ => unreachable!(),
         };
 
         let json = serde_json::to_string(&PartialChatResponse {
+            id: id.clone(),
             object: "chat.completion.chunk".into(),
             model: model_name.clone(),
             choices: vec![choice],
@@ -276,20 +321,21 @@ async fn chat_completions_stream(
         Ok(Event::default().data(json))
     });
 
-    Sse::new(stream)
+    Ok(Sse::new(stream))
 }
 
 pub async fn chat_completions(
     state: State<ThreadState>,
+    usage: Usage,
     Json(request): Json<ChatRequest>,
 ) -> Response {
+    let id = request_id("chatcmpl");
+    let headers = [(REQUEST_ID_HEADER, id.clone())];
     if request.stream {
-        chat_completions_stream(state, Json(request))
-            .await
-            .into_response()
+        let response = chat_completions_stream(state, Json(request), id, usage).await;
+        (headers, response).into_response()
     } else {
-        chat_completions_one(state, Json(request))
-            .await
-            .into_response()
+        let response = chat_completions_one(state, Json(request), id, usage).await;
+        (headers, response).into_response()
     }
 }
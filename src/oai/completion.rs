@@ -1,4 +1,4 @@
-use std::{collections::HashMap, time::Duration};
+use std::collections::HashMap;
 
 use anyhow::Result;
 use axum::{
@@ -8,10 +8,15 @@ use axum::{
 };
 use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use tracing::Span;
 
 use crate::{
-    sampler::Sampler, utils::request_info, Array, FinishReason, GenerateRequest, ThreadRequest,
-    ThreadState, Token, TokenCounter,
+    error::ApiError,
+    sampler::Sampler,
+    usage::Usage,
+    utils::{admit, queue_event, request_id, request_info, REQUEST_ID_HEADER},
+    Array, FinishReason, GenerateRequest, Priority, ThreadRequest, ThreadState, Token,
+    TokenCounter,
 };
 
 #[derive(Debug, Deserialize)]
@@ -27,6 +32,11 @@ pub struct CompletionRequest {
     frequency_penalty: f32,
     penalty_decay: f32,
     logit_bias: HashMap<u16, f32>,
+    model: String,
+    /// Name of a LoRA variant to run the request on instead of `model`; an unknown name gets `model_not_found`.
+    lora: String,
+    user: String,
+    priority: Priority,
 }
 
 impl Default for CompletionRequest {
@@ -42,6 +52,10 @@ impl Default for CompletionRequest {
             frequency_penalty: 0.0,
             penalty_decay: 1.0,
             logit_bias: HashMap::new(),
+            model: String::new(),
+            lora: String::new(),
+            user: String::new(),
+            priority: Priority::default(),
         }
     }
 }
@@ -58,6 +72,10 @@ impl From<CompletionRequest> for GenerateRequest {
             frequency_penalty,
             penalty_decay,
             logit_bias,
+            model,
+            lora,
+            user,
+            priority,
             ..
         } = value;
 
@@ -66,6 +84,12 @@ impl From<CompletionRequest> for GenerateRequest {
         let stop = stop.into();
 
         Self {
+            model: match lora.is_empty() {
+                true => model,
+                false => lora,
+            },
+            client: user,
+            priority,
             prompt,
             max_tokens,
             stop,
@@ -91,6 +115,7 @@ pub struct CompletionChoice {
 
 #[derive(Debug, Serialize)]
 pub struct CompletionResponse {
+    id: String,
     object: String,
     model: String,
     choices: Vec<CompletionChoice>,
@@ -101,26 +126,34 @@ pub struct CompletionResponse {
 async fn completions_one(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<CompletionRequest>,
-) -> Json<CompletionResponse> {
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    id: String,
+    usage: Usage,
+) -> Result<Json<CompletionResponse>, ApiError> {
+    let mut request = GenerateRequest {
+        id: id.clone(),
+        ..request.into()
+    };
+    let info = request_info(sender.clone(), &request.model).await?;
+    usage.tag(&mut request, &info);
+    let model_name = info.reload.model_id();
 
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = GenerateRequest::from(request);
     let _ = sender.send(ThreadRequest::Generate {
-        request,
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
+        span: Span::current(),
     });
 
     let mut token_counter = TokenCounter::default();
     let mut finish_reason = FinishReason::Null;
     let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let mut stream = admit(token_receiver).await?;
 
     while let Some(token) = stream.next().await {
         match token {
-            Token::Start => {}
+            Token::Queue(_) | Token::Start => {}
+            Token::Reject(err) => return Err(err.into()),
             Token::Token(token) => {
                 text += &token;
             }
@@ -133,7 +166,8 @@ async fn completions_one(
         }
     }
 
-    Json(CompletionResponse {
+    Ok(Json(CompletionResponse {
+        id,
         object: "text_completion".into(),
         model: model_name,
         choices: vec![CompletionChoice {
@@ -142,7 +176,7 @@ async fn completions_one(
             finish_reason,
         }],
         counter: token_counter,
-    })
+    }))
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -163,6 +197,7 @@ pub struct PartialCompletionChoice {
 
 #[derive(Debug, Serialize)]
 pub struct PartialCompletionResponse {
+    id: String,
     object: String,
     model: String,
     choices: Vec<PartialCompletionChoice>,
@@ -171,52 +206,68 @@ pub struct PartialCompletionResponse {
 async fn completions_stream(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<CompletionRequest>,
-) -> Sse<impl Stream<Item = Result<Event>>> {
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    id: String,
+    usage: Usage,
+) -> Result<Sse<impl Stream<Item = Result<Event>>>, ApiError> {
+    let mut request = GenerateRequest {
+        id: id.clone(),
+        ..request.into()
+    };
+    let info = request_info(sender.clone(), &request.model).await?;
+    usage.tag(&mut request, &info);
+    let model_name = info.reload.model_id();
 
     let (token_sender, token_receiver) = flume::unbounded();
-    let request = GenerateRequest::from(request);
     let _ = sender.send(ThreadRequest::Generate {
-        request,
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
+        span: Span::current(),
     });
 
-    let stream = token_receiver.into_stream().skip(1).map(move |token| {
-        let choice = match token {
-            Token::Token(token) => PartialCompletionChoice {
-                delta: PartialCompletionRecord::Content(token),
-                ..Default::default()
-            },
-            Token::Stop(finish_reason, _) => PartialCompletionChoice {
-                finish_reason,
-                ..Default::default()
-            },
-            Token::Done => return Ok(Event::default().data("[DONE]")),
-            _ => unreachable!(),
-        };
-
-        let json = serde_json::to_string(&PartialCompletionResponse {
-            object: "text_completion.chunk".into(),
-            model: model_name.clone(),
-            choices: vec![choice],
-        })?;
-        Ok(Event::default().data(json))
-    });
+    let stream = admit(token_receiver).await?;
+    let stream = stream
+        .filter(|token| std::future::ready(!matches!(token, Token::Start)))
+        .map(move |token| {
+            let choice = match token {
+                Token::Token(token) => PartialCompletionChoice {
+                    delta: PartialCompletionRecord::Content(token),
+                    ..Default::default()
+                },
+                Token::Stop(finish_reason, _) => PartialCompletionChoice {
+                    finish_reason,
+                    ..Default::default()
+                },
+                Token::Queue(position) => return Ok(queue_event(position)),
+                Token::Reject(err) => return Ok(ApiError::from(err).into()),
+                Token::Done => return Ok(Event::default().data("[DONE]")),
+                _ => unreachable!(),
+            };
+
+            let json = serde_json::to_string(&PartialCompletionResponse {
+                id: id.clone(),
+                object: "text_completion.chunk".into(),
+                model: model_name.clone(),
+                choices: vec![choice],
+            })?;
+            Ok(Event::default().data(json))
+        });
 
-    Sse::new(stream)
+    Ok(Sse::new(stream))
 }
 
 pub async fn completions(
     state: State<ThreadState>,
+    usage: Usage,
     Json(request): Json<CompletionRequest>,
 ) -> Response {
+    let id = request_id("cmpl");
+    let headers = [(REQUEST_ID_HEADER, id.clone())];
     if request.stream {
-        completions_stream(state, Json(request))
-            .await
-            .into_response()
+        let response = completions_stream(state, Json(request), id, usage).await;
+        (headers, response).into_response()
     } else {
-        completions_one(state, Json(request)).await.into_response()
+        let response = completions_one(state, Json(request), id, usage).await;
+        (headers, response).into_response()
     }
 }
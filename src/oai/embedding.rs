@@ -1,22 +1,39 @@
-use std::time::Duration;
-
-use axum::{extract::State, Json};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use tracing::Span;
 
 use crate::{
-    utils::request_info, Array, GenerateRequest, ThreadRequest, ThreadState, Token, TokenCounter,
+    error::ApiError,
+    usage::Usage,
+    utils::{admit, request_id, request_info, REQUEST_ID_HEADER},
+    Array, GenerateRequest, Priority, ThreadRequest, ThreadState, Token, TokenCounter,
 };
 
 #[derive(Debug, Default, Clone, Deserialize)]
 #[serde(default)]
 pub struct EmbeddingRequest {
     input: Array<String>,
+    model: String,
+    /// Name of a LoRA variant to run the request on instead of `model`; an unknown name gets `model_not_found`.
+    lora: String,
+    user: String,
+    priority: Priority,
 }
 
 impl From<EmbeddingRequest> for GenerateRequest {
     fn from(value: EmbeddingRequest) -> Self {
         Self {
+            model: match value.lora.is_empty() {
+                true => value.model,
+                false => value.lora,
+            },
+            client: value.user,
+            priority: value.priority,
             prompt: Vec::from(value.input).join(""),
             max_tokens: 1,
             embed: true,
@@ -41,26 +58,35 @@ pub struct EmbeddingResponse {
     counter: TokenCounter,
 }
 
-pub async fn embeddings(
+async fn embeddings_one(
     State(ThreadState(sender)): State<ThreadState>,
     Json(request): Json<EmbeddingRequest>,
-) -> Json<EmbeddingResponse> {
-    let info = request_info(sender.clone(), Duration::from_secs(1)).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+    id: String,
+    usage: Usage,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    let mut request = GenerateRequest {
+        id,
+        ..request.into()
+    };
+    let info = request_info(sender.clone(), &request.model).await?;
+    usage.tag(&mut request, &info);
+    let model_name = info.reload.model_id();
 
     let (token_sender, token_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Generate {
-        request: request.into(),
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
+        span: Span::current(),
     });
 
     let mut token_counter = TokenCounter::default();
     let mut embedding = Vec::new();
-    let mut stream = token_receiver.into_stream();
+    let mut stream = admit(token_receiver).await?;
 
     while let Some(token) = stream.next().await {
         match token {
+            Token::Reject(err) => return Err(err.into()),
             Token::Stop(_, counter) => token_counter = counter,
             Token::Embed(emb) => {
                 embedding = emb;
@@ -70,7 +96,7 @@ pub async fn embeddings(
         }
     }
 
-    Json(EmbeddingResponse {
+    Ok(Json(EmbeddingResponse {
         object: "list".into(),
         model: model_name,
         data: vec![EmbeddingData {
@@ -79,5 +105,16 @@ pub async fn embeddings(
             embedding,
         }],
         counter: token_counter,
-    })
+    }))
+}
+
+pub async fn embeddings(
+    state: State<ThreadState>,
+    usage: Usage,
+    Json(request): Json<EmbeddingRequest>,
+) -> Response {
+    let id = request_id("embd");
+    let headers = [(REQUEST_ID_HEADER, id.clone())];
+    let response = embeddings_one(state, Json(request), id, usage).await;
+    (headers, response).into_response()
 }
@@ -1,9 +1,7 @@
-use std::time::Duration;
-
 use axum::{extract::State, Json};
 use serde::Serialize;
 
-use crate::{utils::request_info, ThreadState};
+use crate::{error::ApiError, ThreadRequest, ThreadState};
 
 #[derive(Debug, Serialize)]
 struct ModelChoice {
@@ -16,19 +14,22 @@ pub struct ModelResponse {
     data: Vec<ModelChoice>,
 }
 
-pub async fn models(State(ThreadState(sender)): State<ThreadState>) -> Json<ModelResponse> {
-    let info = request_info(sender, Duration::from_secs(1)).await;
-    let model_name = info
-        .reload
-        .model_path
-        .file_stem()
-        .map(|stem| stem.to_string_lossy())
-        .unwrap_or_default();
+pub async fn models(
+    State(ThreadState(sender)): State<ThreadState>,
+) -> Result<Json<ModelResponse>, ApiError> {
+    let (models_sender, models_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Models(models_sender));
+    let models = models_receiver
+        .recv_async()
+        .await
+        .map_err(|_| ApiError::Internal("failed to list models".into()))?;
 
-    Json(ModelResponse {
-        data: vec![ModelChoice {
+    let data = models
+        .into_iter()
+        .map(|model| ModelChoice {
             object: "models".into(),
-            id: model_name.into(),
-        }],
-    })
+            id: model.model_id(),
+        })
+        .collect();
+    Ok(Json(ModelResponse { data }))
 }
@@ -0,0 +1,431 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use serde::de::DeserializeOwned;
+use web_rwkv::model::{EmbedDevice, Quant};
+
+use crate::config::{AdapterOption, ApiKey, Config, LogFormat, Lora, QuantRange};
+
+/// Parse a value written as in the config, e.g., `NF4` for [`Quant`].
+fn parse_config<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(value.into()))
+        .map_err(|_| format!("invalid value {value}"))
+}
+
+/// Parse a JSON value with the same fields as in the config.
+fn parse_json<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_str(value).map_err(|err| format!("invalid JSON: {err}"))
+}
+
+/// Parse `auto`, `economical` or the index of an adapter.
+fn parse_adapter(value: &str) -> Result<AdapterOption, String> {
+    match value.to_lowercase().as_str() {
+        "auto" => Ok(AdapterOption::Auto),
+        "economical" => Ok(AdapterOption::Economical),
+        index => index
+            .parse()
+            .map(AdapterOption::Manual)
+            .map_err(|_| format!("invalid adapter {value}")),
+    }
+}
+
+/// Parse `LAYERS=TYPE`, e.g., `0-5=NF4`.
+fn parse_quant_range(value: &str) -> Result<QuantRange, String> {
+    let (layers, quant_type) = value
+        .split_once('=')
+        .ok_or(format!("expect LAYERS=TYPE, found {value}"))?;
+    Ok(QuantRange {
+        layers: parse_config(layers.trim())?,
+        quant_type: parse_config(quant_type.trim())?,
+    })
+}
+
+/// Parse `PATH` or `PATH=ALPHA`.
+fn parse_lora(value: &str) -> Result<Lora, String> {
+    match value
+        .rsplit_once('=')
+        .map(|(path, alpha)| (path, alpha.parse()))
+    {
+        Some((path, Ok(alpha))) => Ok(Lora {
+            path: path.into(),
+            alpha,
+        }),
+        _ => Ok(Lora {
+            path: value.into(),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Overrides of the config, from the command line or else from the `AI00_*` environment variables.
+/// They apply to the default model, while the other `models` are only set in the config.
+/// Addresses to listen on are given by `--listen` and `--socket-mode` instead of `listen` in the config.
+#[derive(Args, Debug, Clone)]
+pub struct Overrides {
+    /// Path to the default model.
+    #[arg(
+        long,
+        env = "AI00_MODEL_PATH",
+        value_name = "FILE",
+        help_heading = "Model"
+    )]
+    model_path: Option<PathBuf>,
+    /// Number of layers to be quantized.
+    #[arg(
+        long,
+        env = "AI00_QUANT",
+        value_name = "LAYERS",
+        help_heading = "Model"
+    )]
+    quant: Option<usize>,
+    /// Quantization type: `Int8` or `NF4`.
+    #[arg(long, env = "AI00_QUANT_TYPE", value_name = "TYPE", value_parser = parse_config::<Quant>, help_heading = "Model")]
+    quant_type: Option<Quant>,
+    /// Quantization type of a layer range, e.g., `0-5=NF4`; may be repeated.
+    #[arg(long, env = "AI00_QUANT_LAYERS", value_name = "LAYERS=TYPE", value_parser = parse_quant_range, value_delimiter = ',', help_heading = "Model")]
+    quant_layers: Vec<QuantRange>,
+    /// Whether to use the alternative GEMM kernel to speed-up long prompts.
+    #[arg(long, env = "AI00_TURBO", value_name = "BOOL", help_heading = "Model")]
+    turbo: Option<bool>,
+    /// Maximum tokens to be processed in parallel at once.
+    #[arg(
+        long,
+        env = "AI00_TOKEN_CHUNK_SIZE",
+        value_name = "TOKENS",
+        help_heading = "Model"
+    )]
+    token_chunk_size: Option<usize>,
+    /// Chunk size of each split of the head matrix.
+    #[arg(
+        long,
+        env = "AI00_HEAD_CHUNK_SIZE",
+        value_name = "SIZE",
+        help_heading = "Model"
+    )]
+    head_chunk_size: Option<usize>,
+    /// Maximum number of batches that are active at once.
+    #[arg(
+        long,
+        env = "AI00_MAX_RUNTIME_BATCH",
+        value_name = "BATCH",
+        help_heading = "Model"
+    )]
+    max_runtime_batch: Option<usize>,
+    /// Number of states that are cached on GPU.
+    #[arg(
+        long,
+        env = "AI00_MAX_BATCH",
+        value_name = "BATCH",
+        help_heading = "Model"
+    )]
+    max_batch: Option<usize>,
+    /// The (reversed) number of the layer whose output is the embedding.
+    #[arg(
+        long,
+        env = "AI00_EMBED_LAYER",
+        value_name = "LAYER",
+        help_heading = "Model"
+    )]
+    embed_layer: Option<usize>,
+    /// Device to put the embed tensor: `Cpu` or `Gpu`.
+    #[arg(long, env = "AI00_EMBED_DEVICE", value_name = "DEVICE", value_parser = parse_config::<EmbedDevice>, help_heading = "Model")]
+    embed_device: Option<EmbedDevice>,
+    /// Maximum number of states kept in the backed cache.
+    #[arg(
+        long,
+        env = "AI00_MAX_CACHE_ITEMS",
+        value_name = "ITEMS",
+        help_heading = "Model"
+    )]
+    max_cache_items: Option<usize>,
+    /// Maximum memory used by the backed cache.
+    #[arg(
+        long,
+        env = "AI00_MAX_CACHE_MEMORY",
+        value_name = "MiB",
+        help_heading = "Model"
+    )]
+    max_cache_memory: Option<usize>,
+    /// Prompt whose state is computed at load and never evicted; may be repeated.
+    #[arg(
+        long = "pinned-prompt",
+        env = "AI00_PINNED_PROMPT",
+        value_name = "PROMPT",
+        help_heading = "Model"
+    )]
+    pinned_prompts: Vec<String>,
+    /// LoRA blended into the default model, with an optional blend factor; may be repeated.
+    #[arg(long, env = "AI00_LORA", value_name = "PATH[=ALPHA]", value_parser = parse_lora, help_heading = "Model")]
+    lora: Vec<Lora>,
+    /// Path to the tokenizer of the default model.
+    #[arg(
+        long,
+        env = "AI00_TOKENIZER_PATH",
+        value_name = "FILE",
+        help_heading = "Model"
+    )]
+    tokenizer_path: Option<PathBuf>,
+    /// GPU to use: `auto`, `economical` or the index of an adapter.
+    #[arg(long, env = "AI00_ADAPTER", value_name = "ADAPTER", value_parser = parse_adapter, help_heading = "Model")]
+    adapter: Option<AdapterOption>,
+
+    /// Additional stop word; may be repeated.
+    #[arg(
+        long = "stop",
+        env = "AI00_STOP",
+        value_name = "WORD",
+        help_heading = "Setting"
+    )]
+    stop: Vec<String>,
+    /// Maximum number of requests waiting in the queue; `0` for no limit.
+    #[arg(
+        long,
+        env = "AI00_MAX_QUEUE_DEPTH",
+        value_name = "REQUESTS",
+        help_heading = "Setting"
+    )]
+    max_queue_depth: Option<usize>,
+    /// Maximum seconds a request may wait in the queue; `0` for no limit.
+    #[arg(
+        long,
+        env = "AI00_MAX_QUEUE_WAIT",
+        value_name = "SECS",
+        help_heading = "Setting"
+    )]
+    max_queue_wait: Option<u64>,
+    /// Maximum GPU memory taken by all loaded models; `0` for no limit.
+    #[arg(
+        long,
+        env = "AI00_MAX_MODEL_MEMORY",
+        value_name = "MiB",
+        help_heading = "Setting"
+    )]
+    max_model_memory: Option<usize>,
+    /// Seconds after which an unused model is unloaded; `0` to keep models loaded.
+    #[arg(
+        long,
+        env = "AI00_MODEL_IDLE_TIMEOUT",
+        value_name = "SECS",
+        help_heading = "Setting"
+    )]
+    model_idle_timeout: Option<u64>,
+    /// Seconds the inference loop may stall before `/live` fails; `0` for no limit.
+    #[arg(
+        long,
+        env = "AI00_LIVE_TIMEOUT",
+        value_name = "SECS",
+        help_heading = "Setting"
+    )]
+    live_timeout: Option<u64>,
+    /// Seconds running generations may take to finish on shutdown; `0` for no limit.
+    #[arg(
+        long,
+        env = "AI00_SHUTDOWN_TIMEOUT",
+        value_name = "SECS",
+        help_heading = "Setting"
+    )]
+    shutdown_timeout: Option<u64>,
+    /// File to which the tokens of cached prefixes are saved on shutdown.
+    #[arg(
+        long,
+        env = "AI00_CACHE_PATH",
+        value_name = "FILE",
+        help_heading = "Setting"
+    )]
+    cache_path: Option<PathBuf>,
+    /// File keeping the daily token usage of each API key.
+    #[arg(
+        long,
+        env = "AI00_QUOTA_PATH",
+        value_name = "FILE",
+        help_heading = "Setting"
+    )]
+    quota_path: Option<PathBuf>,
+    /// Append-only log of the usage of all inference requests.
+    #[arg(
+        long,
+        env = "AI00_USAGE_PATH",
+        value_name = "FILE",
+        help_heading = "Setting"
+    )]
+    usage_path: Option<PathBuf>,
+
+    /// Filter directives of log levels, e.g., `warn,ai00_server=debug`.
+    #[arg(long, env = "AI00_LOG", value_name = "FILTER", help_heading = "Log")]
+    log_level: Option<String>,
+    /// Log format: `text` or `json`.
+    #[arg(long, env = "AI00_LOG_FORMAT", value_name = "FORMAT", value_parser = parse_config::<LogFormat>, help_heading = "Log")]
+    log_format: Option<LogFormat>,
+
+    /// API keys as a JSON array of tables like `keys` in the config, e.g., `[{"key": "sk-1", "scopes": ["inference"]}]`.
+    #[arg(long, env = "AI00_API_KEYS", value_name = "JSON", value_parser = parse_json::<Vec<ApiKey>>, help_heading = "Auth")]
+    // the path is spelled out so that clap takes the whole array as one value, instead of `Option<Vec<_>>`
+    // taking a value for each occurrence of the option
+    api_keys: Option<::std::vec::Vec<ApiKey>>,
+}
+
+/// Replace `target` with `value` if it is given.
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+/// Replace `target` with `values` if there is any.
+fn set_all<T>(target: &mut Vec<T>, values: Vec<T>) {
+    if !values.is_empty() {
+        *target = values;
+    }
+}
+
+impl Overrides {
+    pub fn apply(self, config: &mut Config) {
+        let model = &mut config.model;
+        set(&mut model.path, self.model_path);
+        set(&mut model.quant, self.quant);
+        set(&mut model.quant_type, self.quant_type);
+        set_all(&mut model.quant_layers, self.quant_layers);
+        set(&mut model.turbo, self.turbo);
+        set(&mut model.token_chunk_size, self.token_chunk_size);
+        set(&mut model.head_chunk_size, self.head_chunk_size);
+        set(&mut model.max_runtime_batch, self.max_runtime_batch);
+        set(&mut model.max_batch, self.max_batch);
+        set(&mut model.embed_layer, self.embed_layer);
+        set(&mut model.embed_device, self.embed_device);
+        set(&mut model.max_cache_items, self.max_cache_items);
+        set(&mut model.max_cache_memory, self.max_cache_memory);
+        set_all(&mut model.pinned_prompts, self.pinned_prompts);
+        set_all(&mut config.lora, self.lora);
+        set(&mut config.tokenizer.path, self.tokenizer_path);
+        set(&mut config.adapter, self.adapter);
+
+        let setting = &mut config.setting;
+        set_all(&mut setting.stop, self.stop);
+        set(&mut setting.max_queue_depth, self.max_queue_depth);
+        set(&mut setting.max_queue_wait, self.max_queue_wait);
+        set(&mut setting.max_model_memory, self.max_model_memory);
+        set(&mut setting.model_idle_timeout, self.model_idle_timeout);
+        set(&mut setting.live_timeout, self.live_timeout);
+        set(&mut setting.shutdown_timeout, self.shutdown_timeout);
+        set(&mut setting.cache_path, self.cache_path);
+        set(&mut setting.quota_path, self.quota_path);
+        set(&mut setting.usage_path, self.usage_path);
+
+        set(&mut config.log.level, self.log_level);
+        set(&mut config.log.format, self.log_format);
+
+        set(&mut config.keys, self.api_keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        overrides: Overrides,
+    }
+
+    #[test]
+    fn lora_path() {
+        let lora = parse_lora("assets/lora/a.st").unwrap();
+        assert_eq!(lora.path, PathBuf::from("assets/lora/a.st"));
+        assert_eq!(lora.alpha, Lora::default().alpha);
+    }
+
+    #[test]
+    fn lora_path_alpha() {
+        let lora = parse_lora("assets/lora/a.st=0.5").unwrap();
+        assert_eq!(lora.path, PathBuf::from("assets/lora/a.st"));
+        assert_eq!(lora.alpha, 0.5);
+    }
+
+    #[test]
+    fn lora_path_with_equals() {
+        let lora = parse_lora("assets/lora/a=b.st").unwrap();
+        assert_eq!(lora.path, PathBuf::from("assets/lora/a=b.st"));
+        assert_eq!(lora.alpha, Lora::default().alpha);
+    }
+
+    #[test]
+    fn quant_range() {
+        let range = parse_quant_range("0-5 = NF4").unwrap();
+        assert_eq!(range.layers.0, 0..=5);
+        assert!(matches!(range.quant_type, Quant::NF4));
+    }
+
+    #[test]
+    fn quant_range_invalid() {
+        assert!(parse_quant_range("0-5").is_err());
+        assert!(parse_quant_range("0-5=Int4").is_err());
+        assert!(parse_quant_range("a-b=NF4").is_err());
+    }
+
+    #[test]
+    fn adapter() {
+        assert!(matches!(parse_adapter("auto"), Ok(AdapterOption::Auto)));
+        assert!(matches!(
+            parse_adapter("Economical"),
+            Ok(AdapterOption::Economical)
+        ));
+        assert!(matches!(parse_adapter("2"), Ok(AdapterOption::Manual(2))));
+        assert!(parse_adapter("gpu").is_err());
+    }
+
+    #[test]
+    fn command_line_beats_file() {
+        let keys = r#"[{"key": "sk-1", "scopes": ["inference"]}]"#;
+        let args = ["ai00_server", "--max-batch", "32", "--api-keys", keys];
+        let cli = Cli::try_parse_from(args).unwrap();
+
+        let mut config = Config::default();
+        config.model.max_batch = 16;
+        config.model.token_chunk_size = 64;
+        cli.overrides.apply(&mut config);
+
+        assert_eq!(config.model.max_batch, 32);
+        assert_eq!(config.keys.len(), 1);
+        assert_eq!(config.keys[0].key, "sk-1");
+        // the file is kept if no override is given
+        assert_eq!(config.model.token_chunk_size, 64);
+    }
+
+    /// Checked in a child process given the environment variables,
+    /// since setting them in this process races with the other tests.
+    #[test]
+    fn precedence() {
+        const CHILD: &str = "AI00_TEST_PRECEDENCE";
+        if std::env::var_os(CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["overrides::tests::precedence", "--exact"])
+                .env_clear()
+                .env(CHILD, "1")
+                .env("AI00_MAX_BATCH", "8")
+                .env("AI00_MAX_RUNTIME_BATCH", "4")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{stdout}");
+            assert!(stdout.contains("1 passed"), "{stdout}");
+            return;
+        }
+
+        let cli = Cli::try_parse_from(["ai00_server", "--max-batch", "32"]).unwrap();
+        let mut config = Config::default();
+        config.model.max_batch = 16;
+        config.model.max_runtime_batch = 2;
+        config.model.token_chunk_size = 64;
+        cli.overrides.apply(&mut config);
+
+        // the command line beats the environment
+        assert_eq!(config.model.max_batch, 32);
+        // the environment beats the file
+        assert_eq!(config.model.max_runtime_batch, 4);
+        // the file is kept if neither is given
+        assert_eq!(config.model.token_chunk_size, 64);
+    }
+}
@@ -3,18 +3,22 @@ use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
     convert::Infallible,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
     time::Instant,
 };
 
 use anyhow::Result;
 use flume::{Receiver, Sender};
 use itertools::Itertools;
-use qp_trie::Trie;
 use rayon::prelude::{
     IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
-use tokio::sync::{Mutex, RwLock};
+use serde::Serialize;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tracing::Span;
 use web_rwkv::{
     model::{
         v4, v5, v6, BackedState, FromBuilder, Model, ModelInfo, ModelInput, ModelOutput,
@@ -24,8 +28,9 @@ use web_rwkv::{
 };
 
 use crate::{
-    config::Setting, Environment, FinishReason, GenerateRequest, Token, TokenCounter,
-    STATE_CHUNK_SIZE,
+    cache::{state_size, BackedCache, CacheRequest, CacheResponse, CacheStats},
+    config::Setting,
+    metric, Environment, FinishReason, GenerateRequest, Token, TokenCounter, STATE_CHUNK_SIZE,
 };
 
 const PENALTY_FREE_LIST: [&str; 5] = ["\n", ",", ".", "\u{002c}", "\u{002f}"];
@@ -48,8 +53,8 @@ enum SlotState {
     Idle(Tokens, Instant),
     /// The slot is locked and is waiting for processing.
     Wait(Box<GenerateContext>),
-    /// The slot is currently under processing.
-    Busy,
+    /// The slot is currently under processing of the request of the id.
+    Busy(String),
 }
 
 impl Default for SlotState {
@@ -87,6 +92,38 @@ impl std::cmp::PartialOrd for SlotChoice {
     }
 }
 
+/// Order the candidates by weighted fair share, returning their indices.
+///
+/// Each scheduled context costs its client `1 / weight` of its priority, including those already `running`.
+/// The candidate whose client would have the least accumulated cost goes first, and ties are broken by arrival.
+pub fn schedule(candidates: &[&GenerateContext], running: &[&GenerateContext]) -> Vec<usize> {
+    let cost = |context: &GenerateContext| 1.0 / context.request.priority.weight();
+    let mut loads: HashMap<&str, f32> = HashMap::new();
+    for context in running {
+        *loads.entry(&context.request.client).or_default() += cost(context);
+    }
+
+    let mut remain = candidates.iter().copied().enumerate().collect_vec();
+    let mut order = Vec::with_capacity(remain.len());
+    while !remain.is_empty() {
+        let load = |context: &GenerateContext| {
+            let load = loads.get(context.request.client.as_str()).copied();
+            load.unwrap_or_default() + cost(context)
+        };
+        let (pos, _) = remain
+            .iter()
+            .enumerate()
+            .min_by(|(_, (_, x)), (_, (_, y))| {
+                load(x).total_cmp(&load(y)).then(x.time.cmp(&y.time))
+            })
+            .expect("remaining candidates not empty");
+        let (index, context) = remain.remove(pos);
+        *loads.entry(&context.request.client).or_default() += cost(context);
+        order.push(index);
+    }
+    order
+}
+
 #[derive(Debug, Default)]
 pub enum Payload {
     #[default]
@@ -128,6 +165,113 @@ impl Payload {
     }
 }
 
+/// Outcome of one round of [`Runtime::process`].
+#[derive(Debug, Clone, Copy)]
+pub struct Round {
+    /// Some slots are set idle in this round.
+    pub freed: bool,
+    /// No request is in processing after this round.
+    pub idle: bool,
+    /// Slots in each state after scheduling.
+    pub slots: SlotCounts,
+}
+
+impl Default for Round {
+    fn default() -> Self {
+        Self {
+            freed: false,
+            idle: true,
+            slots: Default::default(),
+        }
+    }
+}
+
+/// Snapshot of a slot for introspection.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum SlotInfo {
+    Idle {
+        /// Length of the cached tokens in the slot.
+        prefix: usize,
+        /// Seconds since the slot became idle.
+        age: f32,
+    },
+    Wait {
+        id: String,
+        /// Length of the tokens already computed.
+        prefix: usize,
+        /// Length of the tokens to be computed.
+        suffix: usize,
+        /// Seconds since the request is received.
+        elapsed: f32,
+    },
+    Busy {
+        id: String,
+        prompt_tokens: usize,
+        /// Number of tokens generated so far.
+        completion_tokens: usize,
+        /// Length of the tokens to be computed in the next round.
+        suffix: usize,
+        /// Seconds since the request is received.
+        elapsed: f32,
+    },
+}
+
+/// Snapshot of all slots of a runtime.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlotsInfo {
+    pub max_runtime_batch: usize,
+    /// Number of slots being inferred, bounded by `max_runtime_batch`.
+    pub occupancy: usize,
+    pub slots: Vec<SlotInfo>,
+}
+
+/// Progress of the inference loop, watched by the liveness probe.
+#[derive(Debug, Clone, Copy)]
+pub struct Pulse {
+    /// Whether the loop is processing requests, rather than waiting for them.
+    pub busy: bool,
+    /// When the loop last finished a round, or started or stopped waiting.
+    pub beat: Instant,
+}
+
+impl Default for Pulse {
+    fn default() -> Self {
+        Self {
+            busy: false,
+            beat: Instant::now(),
+        }
+    }
+}
+
+impl Pulse {
+    fn beat(&mut self, busy: bool) {
+        self.busy = busy;
+        self.beat = Instant::now();
+    }
+}
+
+/// Number of slots in each [`SlotState`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SlotCounts {
+    pub idle: usize,
+    pub wait: usize,
+    pub busy: usize,
+}
+
+impl SlotCounts {
+    fn count(slots: &[SlotState]) -> Self {
+        slots.iter().fold(Self::default(), |mut counts, slot| {
+            match slot {
+                SlotState::Idle(_, _) => counts.idle += 1,
+                SlotState::Wait(_) => counts.wait += 1,
+                SlotState::Busy(_) => counts.busy += 1,
+            }
+            counts
+        })
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Default, Clone)]
 pub struct Tokens(pub Vec<u16>);
@@ -224,6 +368,144 @@ pub struct GenerateContext {
     pub request: GenerateRequest,
     /// To send back generated tokens.
     pub sender: Sender<Token>,
+    /// When the request is received.
+    pub time: Instant,
+    /// Span of the HTTP request, which the logs of the context are put under.
+    pub span: Span,
+    /// Counts the request as in flight on its model while it is queued or running.
+    pub in_flight: Option<Arc<InFlight>>,
+    /// Whether the context only computes the state of its prompt for the cache, with no client receiving tokens.
+    pub warm_up: bool,
+}
+
+/// Counts a request among the in-flight requests of a model until it is dropped.
+#[derive(Debug)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, AtomicOrdering::AcqRel);
+        Self(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, AtomicOrdering::AcqRel);
+    }
+}
+
+impl GenerateContext {
+    pub fn new(
+        request: GenerateRequest,
+        tokenizer: &Tokenizer,
+        sender: Sender<Token>,
+        span: Span,
+    ) -> Result<Self> {
+        let tokens = tokenizer.encode(request.prompt.as_bytes())?;
+        Self::with_tokens(request, tokens, tokenizer, sender, span)
+    }
+
+    /// Create the context with the prompt already tokenized, ignoring `request.prompt`.
+    pub fn with_tokens(
+        request: GenerateRequest,
+        tokens: Vec<u16>,
+        tokenizer: &Tokenizer,
+        sender: Sender<Token>,
+        span: Span,
+    ) -> Result<Self> {
+        span.record("generation", &request.id);
+        let tokens = Tokens(tokens);
+        let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
+        let mut penalties = HashMap::new();
+        for (index, token) in model_tokens.iter().rev().enumerate() {
+            let ap = request.sampler.presence_penalty;
+            let af = request.sampler.frequency_penalty;
+            let ad = request.sampler.penalty_decay;
+            let mut penalty = penalties.remove(token).unwrap_or(ap);
+            penalty += af * ad.powf(index as f32);
+            penalties.insert(*token, penalty);
+        }
+
+        Ok(Self {
+            prompt_tokens: tokens.to_vec(),
+            prefix: Default::default(),
+            suffix: tokens,
+            penalties,
+            model_text: Default::default(),
+            output_buffer: Default::default(),
+            model_tokens: Default::default(),
+            request,
+            sender,
+            time: Instant::now(),
+            span,
+            in_flight: None,
+            warm_up: false,
+        })
+    }
+
+    /// Create a context that computes the state of `tokens` so that it is cached.
+    /// It is kept until it finishes, although nothing receives its tokens.
+    pub fn warm_up(tokens: Vec<u16>, span: Span) -> Self {
+        let tokens = Tokens(tokens);
+        let (sender, _) = flume::unbounded();
+        Self {
+            prompt_tokens: tokens.to_vec(),
+            prefix: Default::default(),
+            suffix: tokens,
+            penalties: Default::default(),
+            model_text: Default::default(),
+            output_buffer: Default::default(),
+            model_tokens: Default::default(),
+            request: GenerateRequest {
+                max_tokens: 1,
+                ..Default::default()
+            },
+            sender,
+            time: Instant::now(),
+            span,
+            in_flight: None,
+            warm_up: true,
+        }
+    }
+
+    /// Whether the client has gone, in which case the context is dropped.
+    pub fn abandoned(&self) -> bool {
+        !self.warm_up && self.sender.is_disconnected()
+    }
+
+    pub fn token_counter(&self) -> TokenCounter {
+        let prompt_tokens = self.prompt_tokens.len();
+        let completion_tokens = self.model_tokens.len();
+        let total_tokens = prompt_tokens + completion_tokens;
+        TokenCounter {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+        }
+    }
+
+    /// Account the usage, send the finish reason along with the token counts, and end the token stream.
+    pub fn finish(&self, reason: FinishReason) {
+        if self.warm_up {
+            return;
+        }
+        let counter = self.token_counter();
+        tracing::info!(
+            parent: &self.span,
+            reason = ?reason,
+            prompt_tokens = counter.prompt_tokens,
+            completion_tokens = counter.completion_tokens,
+            elapsed = ?self.time.elapsed(),
+            "finished"
+        );
+        metric::finish(self.time, reason, &counter);
+        if let Some(usage) = &self.request.usage {
+            usage.finish(reason, &counter);
+        }
+        let _ = self.sender.send(Token::Stop(reason, counter));
+        let _ = self.sender.send(Token::Done);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -237,7 +519,9 @@ where
     model: Arc<M>,
     state: Arc<S>,
     slots: Arc<Mutex<Vec<SlotState>>>,
-    backed: Arc<Mutex<Trie<Tokens, B>>>,
+    payloads: Arc<Mutex<Vec<Payload>>>,
+    backed: Arc<Mutex<BackedCache<B>>>,
+    cancelled: Arc<Mutex<HashSet<String>>>,
     max_runtime_batch: usize,
     embed_layer: usize,
     penalty_free_tokens: HashSet<u16>,
@@ -255,7 +539,14 @@ where
         state: S,
         max_runtime_batch: usize,
         embed_layer: usize,
+        max_cache_items: usize,
+        max_cache_memory: usize,
     ) -> Self {
+        let backed = BackedCache::new(
+            state_size(model.info()),
+            max_cache_items,
+            max_cache_memory << 20,
+        );
         let tokenizer = Arc::new(tokenizer);
         let model = Arc::new(model);
         let state = Arc::new(state);
@@ -276,7 +567,9 @@ where
             model,
             state,
             slots: Arc::new(Mutex::new(slots)),
-            backed: Arc::new(Mutex::new(Trie::new())),
+            payloads: Default::default(),
+            backed: Arc::new(Mutex::new(backed)),
+            cancelled: Default::default(),
             max_runtime_batch,
             embed_layer,
             penalty_free_tokens,
@@ -291,10 +584,139 @@ where
         self.tokenizer.clone()
     }
 
+    pub async fn cache_stats(&self) -> CacheStats {
+        self.backed.lock().await.stats()
+    }
+
+    /// Manage the backed cache. Note that pinning only registers the prompts here;
+    /// their states are computed when they are run as normal generations.
+    pub async fn cache(&self, request: CacheRequest) -> Result<CacheResponse> {
+        let mut cache = self.backed.lock().await;
+        match request {
+            CacheRequest::List => {}
+            CacheRequest::Evict(ids) => cache.remove(&ids),
+            CacheRequest::Flush => cache.flush(),
+            CacheRequest::Pin(prompts) => {
+                for prompt in prompts {
+                    cache.pin(self.tokenizer.encode(prompt.as_bytes())?);
+                }
+            }
+            CacheRequest::Unpin(ids) => cache.unpin(&ids),
+        }
+
+        let stats = cache.stats();
+        let items = cache.entries(|tokens| {
+            let text = self.tokenizer.decode(tokens).unwrap_or_default();
+            String::from_utf8_lossy(&text).into()
+        });
+        Ok(CacheResponse { stats, items })
+    }
+
+    /// Tokens of all cached prefixes.
+    pub async fn cached_tokens(&self) -> Vec<Vec<u16>> {
+        self.backed.lock().await.prefixes()
+    }
+
+    /// Run a token through the model to check that inference works, before the runtime serves any request.
+    pub async fn probe(&self) -> Result<()> {
+        let mut inputs = (0..self.state.max_batch())
+            .map(|batch| ModelInput {
+                tokens: match batch {
+                    0 => vec![0],
+                    _ => vec![],
+                },
+                ..Default::default()
+            })
+            .collect_vec();
+        while self.model.run(&mut inputs, &self.state).await?[0].is_none() {}
+
+        // clear the state left by the probe
+        let context = self.model.context();
+        let info = self.model.info();
+        let backed = StateBuilder::new(context, info)
+            .with_max_batch(1)
+            .with_chunk_size(STATE_CHUNK_SIZE)
+            .build_backed();
+        self.state.load_batch(&backed, 0)?;
+        Ok(())
+    }
+
+    /// Take a snapshot of all slots, which waits for the current round of [`Runtime::process`] to finish.
+    pub async fn slots(&self) -> SlotsInfo {
+        let payloads = self.payloads.lock().await;
+        let slots = self.slots.lock().await;
+
+        let slots = slots
+            .iter()
+            .enumerate()
+            .map(|(batch, slot)| match (slot, payloads.get(batch)) {
+                (SlotState::Idle(content, time), _) => SlotInfo::Idle {
+                    prefix: content.len(),
+                    age: time.elapsed().as_secs_f32(),
+                },
+                (SlotState::Wait(context), _) => SlotInfo::Wait {
+                    id: context.request.id.clone(),
+                    prefix: context.prefix.len(),
+                    suffix: context.suffix.len(),
+                    elapsed: context.time.elapsed().as_secs_f32(),
+                },
+                (SlotState::Busy(_), Some(Payload::Busy(context) | Payload::Done(context))) => {
+                    SlotInfo::Busy {
+                        id: context.request.id.clone(),
+                        prompt_tokens: context.prompt_tokens.len(),
+                        completion_tokens: context.model_tokens.len(),
+                        suffix: context.suffix.len(),
+                        elapsed: context.time.elapsed().as_secs_f32(),
+                    }
+                }
+                // the payload is yet to be taken from the slot in the next round
+                (SlotState::Busy(id), _) => SlotInfo::Busy {
+                    id: id.clone(),
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                    suffix: 0,
+                    elapsed: 0.0,
+                },
+            })
+            .collect();
+        let occupancy = payloads.iter().filter(|x| x.is_busy()).count();
+
+        SlotsInfo {
+            max_runtime_batch: self.max_runtime_batch,
+            occupancy,
+            slots,
+        }
+    }
+
+    /// Cancel the request of `id` if it is waiting or running on a slot.
+    /// Returns `false` if there is no such request.
+    pub async fn cancel(&self, id: &str) -> bool {
+        let mut slots = self.slots.lock().await;
+        for slot in slots.iter_mut() {
+            match slot {
+                SlotState::Wait(context) if context.request.id == id => {
+                    // the state of the prefix is already loaded, so the slot is reusable
+                    context.finish(FinishReason::Cancelled);
+                    let prefix = std::mem::take(&mut context.prefix);
+                    *slot = SlotState::Idle(prefix, Instant::now());
+                    return true;
+                }
+                SlotState::Busy(busy) if busy == id => {
+                    // the payload is finalized on the next process
+                    self.cancelled.lock().await.insert(id.into());
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
     /// Queue an inference task.
     pub async fn queue(&self, context: GenerateContext) -> SlotResult {
         let mut slots = self.slots.lock().await;
         let mut cache = self.backed.lock().await;
+        let span = context.span.clone();
 
         // we must ensure that there is at least one token as the suffix, otherwise the whole slot will loop forever as there is no input
         let (last, tokens) = match [context.prefix, context.suffix].concat().split_last() {
@@ -325,28 +747,21 @@ where
         // here we try to search for the longest common prefix in the memory cache and checkout the state from that point
         // should there be a cache miss, an initial state is returned
         let mut checkout = |batch: usize| -> (Vec<u16>, B) {
-            let prefix = cache.longest_common_prefix(tokens.as_token_slice());
-            let len = (1..=prefix.len())
-                .rev()
-                .find(|len| cache.contains_key(prefix[0..*len].as_token_slice()))
-                .unwrap_or_default();
-            log::info!("slot {} checks out backed cache of length {}", batch, len);
-
-            let prefix = prefix[0..len].to_vec();
-            let reload = cache
-                .remove(prefix[..].as_token_slice())
-                .unwrap_or_else(|| {
-                    let context = self.model.context();
-                    let info = self.model.info();
-                    StateBuilder::new(context, info)
-                        .with_max_batch(1)
-                        .with_chunk_size(STATE_CHUNK_SIZE)
-                        .build_backed()
-                });
-            if len > 0 {
-                let key = Tokens(prefix.clone());
-                cache.insert(key, reload.clone());
-            }
+            let (prefix, reload) = cache.checkout(&tokens).unwrap_or_else(|| {
+                let context = self.model.context();
+                let info = self.model.info();
+                let backed = StateBuilder::new(context, info)
+                    .with_max_batch(1)
+                    .with_chunk_size(STATE_CHUNK_SIZE)
+                    .build_backed();
+                (vec![], backed)
+            });
+            tracing::info!(
+                parent: &span,
+                batch,
+                prefix = prefix.len(),
+                "checked out backed cache"
+            );
             (prefix, reload)
         };
 
@@ -363,7 +778,7 @@ where
             ),
             // back a non-relative and non-empty slot and use it for our new context
             Some((SlotChoice::Back(batch), _)) => {
-                log::info!("start at non-empty slot {}", batch);
+                tracing::info!(parent: &span, batch, "start at non-empty slot");
                 let (prefix, reload) = checkout(batch);
 
                 let tokens = [tokens, vec![last]].concat();
@@ -390,7 +805,7 @@ where
             }
             // directly occupy an empty slot so no need backing
             Some((SlotChoice::Empty(batch), _)) => {
-                log::info!("start at empty slot {}", batch);
+                tracing::info!(parent: &span, batch, "start at empty slot");
                 let (prefix, reload) = checkout(batch);
 
                 let tokens = [tokens, vec![last]].concat();
@@ -410,7 +825,7 @@ where
             }
             // continue from an existing slot. No need backing as well
             Some((SlotChoice::Continue(batch, len), _)) => {
-                log::info!("continue at slot {}", batch);
+                tracing::info!(parent: &span, batch, prefix = len, "continue at slot");
                 let tokens = [tokens, vec![last]].concat();
                 let state = SlotState::Wait(
                     GenerateContext {
@@ -426,31 +841,50 @@ where
         }
     }
 
-    pub async fn process(&self, payloads: &mut Vec<Payload>, setting: &Setting) -> Result<()> {
-        {
+    pub async fn process(&self, setting: &Setting) -> Result<Round> {
+        let mut payloads = self.payloads.lock().await;
+        let payloads = &mut *payloads;
+        // slots of finished payloads are set idle in this round
+        let freed = payloads.iter().any(Payload::is_done);
+
+        let slots = {
             let mut slots = self.slots.lock().await;
             let mut cache = self.backed.lock().await;
 
             payloads.resize_with(self.state.max_batch(), Default::default);
             // sync payloads and slots: kill dead payloads
             for (slot, payload) in slots.iter().zip_eq(payloads.iter_mut()) {
-                if !payload.is_empty() && !matches!(slot, SlotState::Busy) {
+                if !payload.is_empty() && !matches!(slot, SlotState::Busy(_)) {
                     *payload = Payload::Empty;
                 }
             }
 
+            // finalize cancelled payloads so that their slots are freed below
+            let mut cancelled = self.cancelled.lock().await;
+            for payload in payloads.iter_mut() {
+                if let Payload::Busy(context) = payload {
+                    if cancelled.contains(&context.request.id) {
+                        tracing::info!(parent: &context.span, "cancelled");
+                        context.finish(FinishReason::Cancelled);
+                        payload.finalize();
+                    }
+                }
+            }
+            cancelled.clear();
+            drop(cancelled);
+
             // reset all finished slots to idle
             for (batch, payload) in payloads.iter_mut().enumerate() {
                 let Some(context) = payload.take() else {
                     continue;
                 };
 
-                assert!(matches!(slots[batch], SlotState::Busy));
+                assert!(matches!(slots[batch], SlotState::Busy(_)));
+                tracing::info!(parent: &context.span, batch, "backed slot");
                 slots[batch] = SlotState::Idle(context.prefix, Instant::now());
 
                 if let Some(backed) = match &slots[batch] {
                     SlotState::Idle(content, _) => {
-                        log::info!("backed slot {}", batch);
                         let backed = self.state.back_batch(batch).await.expect("back state");
                         cache.insert(content.clone(), backed.clone());
                         Some(backed)
@@ -470,18 +904,33 @@ where
                 .filter(|x| matches!(x, Payload::Busy(_)))
                 .count();
             let remain = self.max_runtime_batch - self.max_runtime_batch.min(occupancy);
-            let batches = slots
+            let running = payloads
+                .iter()
+                .filter_map(|payload| match payload {
+                    Payload::Busy(context) => Some(context.as_ref()),
+                    _ => None,
+                })
+                .collect_vec();
+            let waiting = slots
                 .iter()
                 .enumerate()
-                .filter(|(_, slot)| matches!(slot, SlotState::Wait(_)))
+                .filter_map(|(batch, slot)| match slot {
+                    SlotState::Wait(context) => Some((batch, context.as_ref())),
+                    _ => None,
+                })
+                .collect_vec();
+            let contexts = waiting.iter().map(|(_, context)| *context).collect_vec();
+            let batches = schedule(&contexts, &running)
+                .into_iter()
                 .take(remain)
-                .map(|(batch, _)| batch)
+                .map(|index| waiting[index].0)
                 .collect_vec();
             for batch in batches {
-                let mut slot = SlotState::Busy;
+                let mut slot = SlotState::Busy(Default::default());
                 std::mem::swap(&mut slots[batch], &mut slot);
                 match slot {
                     SlotState::Wait(context) => {
+                        slots[batch] = SlotState::Busy(context.request.id.clone());
                         let _ = context.sender.send(Token::Start);
                         assert!(matches!(payloads[batch], Payload::Empty));
                         payloads[batch] = Payload::Busy(context);
@@ -489,6 +938,8 @@ where
                     _ => unreachable!(),
                 };
             }
+
+            SlotCounts::count(&slots)
         };
 
         let mut inputs = payloads
@@ -602,6 +1053,9 @@ where
             context.model_text.append(&mut word.clone());
             context.output_buffer.append(&mut word);
             context.model_tokens.push(token);
+            if context.model_tokens.len() == 1 && !context.warm_up {
+                metric::first_token(context.time);
+            }
 
             // if let Ok(word) = String::from_utf8(context.output_buffer.clone()) {
             //     let _ = context.sender.send(Token::Token(word));
@@ -609,21 +1063,9 @@ where
             // }
 
             // let model_text = String::from_utf8_lossy(&context.model_text);
-            let count_tokens = || {
-                let prompt_tokens = context.prompt_tokens.len();
-                let completion_tokens = context.model_tokens.len();
-                let total_tokens = prompt_tokens + completion_tokens;
-                TokenCounter {
-                    prompt_tokens,
-                    completion_tokens,
-                    total_tokens,
-                }
-            };
-
             let mut done = false;
             let mut finish = |reason| {
-                let _ = context.sender.send(Token::Stop(reason, count_tokens()));
-                let _ = context.sender.send(Token::Done);
+                context.finish(reason);
                 done = true;
             };
 
@@ -667,8 +1109,8 @@ where
                 .unwrap_or((context.output_buffer.len(), false));
             let output = context.output_buffer[..output_pointer].to_vec();
 
-            if context.sender.is_disconnected() {
-                done = true;
+            if context.abandoned() {
+                finish(FinishReason::Cancelled);
             } else if stop_matched {
                 let output = String::from_utf8_lossy(&output);
                 let _ = context.sender.send(Token::Token(output.into()));
@@ -683,7 +1125,8 @@ where
             done.then(|| payload.finalize());
         }
 
-        Ok(())
+        let idle = payloads.iter().all(Payload::is_empty);
+        Ok(Round { freed, idle, slots })
     }
 }
 
@@ -710,6 +1153,48 @@ macro_rules! impl_runtime_untyped {
                 }
             }
 
+            #[inline]
+            pub async fn cache_stats(&self) -> CacheStats {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.cache_stats().await,)*
+                }
+            }
+
+            #[inline]
+            pub async fn cache(&self, request: CacheRequest) -> Result<CacheResponse> {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.cache(request).await,)*
+                }
+            }
+
+            #[inline]
+            pub async fn cached_tokens(&self) -> Vec<Vec<u16>> {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.cached_tokens().await,)*
+                }
+            }
+
+            #[inline]
+            pub async fn probe(&self) -> Result<()> {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.probe().await,)*
+                }
+            }
+
+            #[inline]
+            pub async fn slots(&self) -> SlotsInfo {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.slots().await,)*
+                }
+            }
+
+            #[inline]
+            pub async fn cancel(&self, id: &str) -> bool {
+                match self {
+                    $(RuntimeUntyped::$variant(runtime) => runtime.cancel(id).await,)*
+                }
+            }
+
             #[inline]
             pub async fn queue(&self, context: GenerateContext) -> SlotResult {
                 match self {
@@ -718,9 +1203,9 @@ macro_rules! impl_runtime_untyped {
             }
 
             #[inline]
-            pub async fn process(&self, payloads: &mut Vec<Payload>, setting: &Setting) -> Result<()> {
+            pub async fn process(&self, setting: &Setting) -> Result<Round> {
                 match self {
-                    $(RuntimeUntyped::$variant(runtime) => runtime.process(payloads, setting).await,)*
+                    $(RuntimeUntyped::$variant(runtime) => runtime.process(setting).await,)*
                 }
             }
         }
@@ -730,18 +1215,56 @@ macro_rules! impl_runtime_untyped {
 impl_runtime_untyped!(V4, V5, V6);
 
 #[tokio::main]
-pub async fn run(receiver: Receiver<()>, env: Arc<RwLock<Environment<'_>>>, setting: Setting) {
+pub async fn run(
+    receiver: Receiver<()>,
+    env: Arc<RwLock<Environment<'_>>>,
+    setting: Setting,
+    notify: Arc<Notify>,
+    model: String,
+    pulse: Arc<RwLock<Pulse>>,
+) {
     while let Ok(()) = receiver.recv_async().await {
-        let mut payloads = vec![];
+        pulse.write().await.beat(true);
         'run: loop {
-            if let Environment::Loaded { runtime, .. } = &*env.read().await {
-                if let Err(err) = runtime.process(&mut payloads, &setting).await {
-                    log::error!("{}", err);
-                }
+            let round = match &*env.read().await {
+                Environment::Loaded { runtime, .. } => runtime.process(&setting).await,
+                Environment::None => Ok(Round::default()),
+            };
+            let round = round.unwrap_or_else(|err| {
+                tracing::error!("{}", err);
+                Round::default()
+            });
+            metric::slots(&model, round.slots);
+            pulse.write().await.beat(!round.idle);
+            if round.freed {
+                notify.notify_one();
             }
-            if payloads.iter().all(Payload::is_empty) {
+            if round.idle {
                 break 'run;
             }
         }
     }
 }
+
+/// Probe a runtime before it serves any request, on a thread of its own since the inference futures are not `Send`.
+#[tokio::main]
+pub async fn probe(runtime: RuntimeUntyped<'_>) -> Result<RuntimeUntyped<'_>> {
+    runtime.probe().await?;
+    Ok(runtime)
+}
+
+/// Keep processing a runtime that is swapped out until all its in-flight requests are finished.
+#[tokio::main]
+pub async fn drain(runtime: RuntimeUntyped<'_>, setting: Setting) {
+    loop {
+        match runtime.process(&setting).await {
+            Ok(Round { idle: false, .. }) => continue,
+            Ok(_) => break,
+            Err(err) => {
+                tracing::error!("{}", err);
+                break;
+            }
+        }
+    }
+    tracing::info!("old runtime drained");
+}
@@ -0,0 +1,94 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::Result;
+use flume::Sender;
+use tokio::sync::watch;
+
+use crate::ThreadRequest;
+
+/// How long the models may take to report their cached prompts.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait until the process is asked to terminate by `SIGINT` or `SIGTERM`.
+pub async fn signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("failed to listen to SIGTERM: {}", err);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Tells the endless event streams that the server is shutting down, so that they end and let their connections close.
+#[derive(Debug, Clone)]
+pub struct Closing(watch::Receiver<bool>);
+
+impl Closing {
+    /// Create the notice along with its sender, which starts the shutdown by sending `true`.
+    pub fn channel() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    /// Wait until the server starts shutting down.
+    pub async fn wait(mut self) {
+        let _ = self.0.wait_for(|closing| *closing).await;
+    }
+}
+
+/// Tokens of the cached prefixes by model, saved on shutdown so that they are computed again when loaded.
+/// Tokens are kept rather than texts, since decoding and encoding again may not give back the same tokens.
+fn read_cache(path: &Path) -> Result<HashMap<String, Vec<Vec<u16>>>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The tokens of the cached prefixes of `model` saved at the last shutdown.
+pub fn cached_tokens(path: &Path, model: &str) -> Result<Vec<Vec<u16>>> {
+    let mut prompts = read_cache(path)?;
+    Ok(prompts.remove(model).unwrap_or_default())
+}
+
+/// Save the tokens of the cached prefixes of all loaded models, keeping those of the models not loaded.
+pub async fn save_cache(sender: &Sender<ThreadRequest>, path: &Path) -> Result<()> {
+    let (dump_sender, dump_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Dump(dump_sender));
+
+    let mut prompts = read_cache(path)?;
+    let collect = async {
+        while let Ok((model, cached)) = dump_receiver.recv_async().await {
+            prompts.insert(model, cached);
+        }
+    };
+    if tokio::time::timeout(DUMP_TIMEOUT, collect).await.is_err() {
+        tracing::warn!("not all models reported their caches in time");
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec(&prompts)?)?;
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::{io, net::SocketAddr};
+
+use axum::{
+    extract::Host,
+    http::{uri::Authority, Uri},
+    response::Redirect,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::config::Tls;
+
+/// Load the certificate and key, which are reloaded whenever the process receives `SIGHUP`.
+pub async fn load(tls: &Tls) -> io::Result<RustlsConfig> {
+    let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let config = config.clone();
+        let Tls { cert, key, .. } = tls.clone();
+        let task = async move {
+            while hangup.recv().await.is_some() {
+                match config.reload_from_pem_file(&cert, &key).await {
+                    Ok(()) => tracing::info!("reloaded certificate {}", cert.to_string_lossy()),
+                    Err(err) => tracing::error!("failed to reload certificate: {}", err),
+                }
+            }
+        };
+        tokio::spawn(task);
+    }
+
+    Ok(config)
+}
+
+/// Serve plain HTTP at `addr`, redirecting every request to HTTPS at `port` of the same host.
+pub async fn redirect(addr: SocketAddr, port: u16) -> io::Result<()> {
+    let redirect = move |Host(host): Host, uri: Uri| async move {
+        let host = host
+            .parse::<Authority>()
+            .map(|authority| authority.host().to_owned())
+            .unwrap_or(host);
+        let path = uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        Redirect::permanent(&format!("https://{host}:{port}{path}"))
+    };
+    let app = Router::new().fallback(redirect);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("redirecting http at {addr} to https");
+    axum::serve(listener, app).await
+}
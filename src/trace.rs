@@ -0,0 +1,70 @@
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    config::{Log, LogFormat},
+    utils::{request_id, REQUEST_ID_HEADER},
+};
+
+/// Longest request ID accepted from the client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Install the global subscriber, which also collects records of the `log` crate from dependencies.
+pub fn init(log: &Log) -> Result<()> {
+    let filter = EnvFilter::try_new(&log.level)?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match log.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|err| anyhow!(err))
+}
+
+/// Run each request in a span carrying its ID, which is taken from the `x-request-id` header if given.
+/// Generations keep the span and record their IDs in it, so that the logs of queueing, slot choice and finish can be told by the request.
+pub async fn request(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(Into::into)
+        .unwrap_or_else(|| request_id("req"));
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        generation = tracing::field::Empty,
+    );
+
+    let start = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    tracing::debug!(
+        parent: &span,
+        status = response.status().as_u16(),
+        latency = ?start.elapsed(),
+        "responded"
+    );
+
+    // generations report their own IDs, by which they are cancelled
+    let headers = response.headers_mut();
+    if let (false, Ok(id)) = (
+        headers.contains_key(REQUEST_ID_HEADER),
+        HeaderValue::try_from(id),
+    ) {
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), id);
+    }
+    response
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use flume::Sender;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+
+use crate::{
+    auth::Client, config::ApiKey, limit::Meter, listen::Connection, FinishReason, GenerateRequest,
+    Priority, RuntimeInfo, TokenCounter,
+};
+
+/// Usage of a finished inference request, appended as a line of the usage log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// Seconds since the Unix epoch when the request finished.
+    pub time: u64,
+    pub id: String,
+    /// Name of the API key, or a digest of the key if it has no name; empty if the API is open.
+    pub key: String,
+    /// The `user` field of the request.
+    pub user: String,
+    pub model: String,
+    pub finish_reason: FinishReason,
+    #[serde(flatten)]
+    pub counter: TokenCounter,
+    /// Seconds from receiving the request to its finish.
+    pub latency: f32,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageQuery {
+    /// First day (`YYYY-MM-DD`, UTC) included; empty for no bound.
+    pub from: String,
+    /// Last day (`YYYY-MM-DD`, UTC) included; empty for no bound.
+    pub to: String,
+    /// Only count requests of this API key if not empty.
+    pub key: String,
+    /// Only count requests of this model if not empty.
+    pub model: String,
+}
+
+/// Usage of a day aggregated by API key and model.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UsageSummary {
+    pub day: String,
+    pub key: String,
+    pub model: String,
+    pub requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    /// Sum of the latencies of the requests in seconds.
+    pub latency: f32,
+}
+
+/// The date (`YYYY-MM-DD`, UTC) of the seconds since the Unix epoch.
+fn date(secs: u64) -> String {
+    // days to the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Usage summaries keyed by day, API key and model.
+type Summaries = BTreeMap<(String, String, String), UsageSummary>;
+
+/// Add the record to the summary of its day, API key and model.
+fn aggregate(summaries: &mut Summaries, record: &UsageRecord) {
+    let day = date(record.time);
+    let summary = summaries
+        .entry((day.clone(), record.key.clone(), record.model.clone()))
+        .or_insert_with(|| UsageSummary {
+            day,
+            key: record.key.clone(),
+            model: record.model.clone(),
+            ..Default::default()
+        });
+    summary.requests += 1;
+    summary.prompt_tokens += record.counter.prompt_tokens;
+    summary.completion_tokens += record.counter.completion_tokens;
+    summary.total_tokens += record.counter.total_tokens;
+    summary.latency += record.latency;
+}
+
+/// Aggregate the records in the log written by earlier runs.
+fn read_summaries(path: &Path) -> Result<Summaries> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut summaries = Summaries::new();
+    for line in BufReader::new(file).lines() {
+        // skip lines torn by a crash
+        if let Ok(record) = serde_json::from_str::<UsageRecord>(&line?) {
+            aggregate(&mut summaries, &record);
+        }
+    }
+    Ok(summaries)
+}
+
+/// A message to the task writing the usage log.
+#[derive(Debug)]
+enum Entry {
+    Record(UsageRecord),
+    /// Flush the records written so far to the file, then reply.
+    Flush(Sender<()>),
+}
+
+/// An append-only log of the usage of all inference requests, in JSON lines.
+/// The log is read once at start, after which its summaries are kept up to date in memory.
+#[derive(Debug, Clone)]
+pub struct UsageLog {
+    sender: Sender<Entry>,
+    summaries: Arc<RwLock<Summaries>>,
+}
+
+impl UsageLog {
+    pub fn new(path: PathBuf) -> Self {
+        let (sender, receiver) = flume::unbounded::<Entry>();
+        let summaries: Arc<RwLock<Summaries>> = Default::default();
+        // queries wait until the earlier records are read
+        let mut guard = summaries
+            .clone()
+            .try_write_owned()
+            .expect("fresh lock is free");
+        let task = {
+            let summaries = summaries.clone();
+            async move {
+                let read = {
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || read_summaries(&path)).await
+                };
+                match read.map_err(anyhow::Error::from).and_then(|read| read) {
+                    Ok(summaries) => *guard = summaries,
+                    Err(err) => tracing::error!("failed to read usage log: {}", err),
+                }
+                drop(guard);
+
+                let mut file = None;
+                while let Ok(entry) = receiver.recv_async().await {
+                    match entry {
+                        Entry::Record(record) => {
+                            if let Err(err) = Self::append(&path, &mut file, &record).await {
+                                tracing::error!("failed to log usage: {}", err);
+                            }
+                            aggregate(&mut *summaries.write().await, &record);
+                        }
+                        Entry::Flush(sender) => {
+                            if let Some(file) = &mut file {
+                                if let Err(err) = file.flush().await {
+                                    tracing::error!("failed to flush usage log: {}", err);
+                                }
+                            }
+                            let _ = sender.send(());
+                        }
+                    }
+                }
+            }
+        };
+        tokio::spawn(task);
+        Self { sender, summaries }
+    }
+
+    async fn append(
+        path: &Path,
+        file: &mut Option<tokio::fs::File>,
+        record: &UsageRecord,
+    ) -> Result<()> {
+        let file = match file {
+            Some(file) => file,
+            None => {
+                if let Some(dir) = path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                let opened = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.insert(opened)
+            }
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line).await?;
+        Ok(())
+    }
+
+    /// Wait until all the records sent so far are written to the file.
+    /// Used on shutdown instead of closing the channel, since contexts still draining hold senders to it.
+    pub async fn flush(&self) {
+        let (sender, receiver) = flume::bounded(1);
+        if self.sender.send(Entry::Flush(sender)).is_ok() {
+            let _ = receiver.recv_async().await;
+        }
+    }
+
+    /// Usage by day, API key and model.
+    pub async fn summarize(&self, query: UsageQuery) -> Vec<UsageSummary> {
+        self.summaries
+            .read()
+            .await
+            .values()
+            .filter(|summary| {
+                (query.from.is_empty() || summary.day >= query.from)
+                    && (query.to.is_empty() || summary.day <= query.to)
+                    && (query.key.is_empty() || summary.key == query.key)
+                    && (query.model.is_empty() || summary.model == query.model)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Accounts the usage of an inference request to the usage log and to the limits of its API key.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    log: Option<UsageLog>,
+    meter: Option<Meter>,
+    key: Option<String>,
+    /// Highest priority allowed by the API key.
+    priority: Option<Priority>,
+    /// Address of the client if it is connected over TCP.
+    peer: Option<SocketAddr>,
+    /// Connection of the client if it is connected over a Unix domain socket.
+    connection: Option<Connection>,
+    start: Instant,
+    id: String,
+    user: String,
+    model: String,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Usage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            log: parts.extensions.get::<UsageLog>().cloned(),
+            meter: parts.extensions.get::<Meter>().cloned(),
+            key: parts
+                .extensions
+                .get::<Client>()
+                .map(|Client(key)| key.clone()),
+            priority: parts.extensions.get::<ApiKey>().map(|key| key.priority),
+            peer: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| *addr),
+            connection: parts.extensions.get::<Connection>().copied(),
+            start: Instant::now(),
+            id: Default::default(),
+            user: Default::default(),
+            model: Default::default(),
+        })
+    }
+}
+
+impl Usage {
+    /// Tag the usage with the request and the model serving it, and attach it to the request to be accounted
+    /// when the generation finishes.
+    /// The request is then scheduled as a client of its API key, or else of its address or its connection
+    /// over a Unix domain socket, instead of its `user`, and its priority is capped by the key.
+    pub fn tag(mut self, request: &mut GenerateRequest, info: &RuntimeInfo) {
+        self.id = request.id.clone();
+        self.user = request.client.clone();
+        self.model = info.reload.model_id();
+        request.client = match (&self.key, self.peer, self.connection) {
+            (Some(key), _, _) => key.clone(),
+            (None, Some(peer), _) => peer.ip().to_string(),
+            (None, None, Some(Connection(id))) => format!("unix-{id}"),
+            (None, None, None) => String::new(),
+        };
+        if let Some(max) = self.priority {
+            request.priority = request.priority.cap(max);
+        }
+        request.usage = Some(self);
+    }
+
+    pub fn finish(&self, finish_reason: FinishReason, counter: &TokenCounter) {
+        if let Some(meter) = &self.meter {
+            meter.record(counter);
+        }
+        if let Some(log) = &self.log {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let _ = log.sender.send(Entry::Record(UsageRecord {
+                time,
+                id: self.id.clone(),
+                key: self.key.clone().unwrap_or_default(),
+                user: self.user.clone(),
+                model: self.model.clone(),
+                finish_reason,
+                counter: counter.clone(),
+                latency: self.start.elapsed().as_secs_f32(),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_day_boundaries() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(86399), "1970-01-01");
+        assert_eq!(date(86400), "1970-01-02");
+    }
+
+    #[test]
+    fn date_month_boundaries() {
+        assert_eq!(date(2678399), "1970-01-31");
+        assert_eq!(date(2678400), "1970-02-01");
+        assert_eq!(date(1704067199), "2023-12-31");
+        assert_eq!(date(1704067200), "2024-01-01");
+    }
+
+    #[test]
+    fn date_leap_years() {
+        // divisible by 400
+        assert_eq!(date(951782399), "2000-02-28");
+        assert_eq!(date(951782400), "2000-02-29");
+        assert_eq!(date(951868800), "2000-03-01");
+        // divisible by 4
+        assert_eq!(date(1709208000), "2024-02-29");
+        // divisible by 100 but not by 400
+        assert_eq!(date(4107542399), "2100-02-28");
+        assert_eq!(date(4107542400), "2100-03-01");
+    }
+}
@@ -1,33 +1,73 @@
 use std::time::Duration;
 
 use anyhow::Result;
-use flume::Sender;
+use axum::response::sse::Event;
+use flume::{Receiver, Sender};
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
 
-use crate::{RuntimeInfo, ThreadRequest};
+use crate::{error::ApiError, LoadPhase, RuntimeInfo, ThreadRequest, Token};
 
-pub async fn try_request_info(sender: Sender<ThreadRequest>) -> Result<RuntimeInfo> {
+/// Header that carries the id of a request, which is the id of the generation for inference requests.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How long a request waits for a model to be loaded before failing.
+pub const MODEL_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Generate a unique request id in the form of `{prefix}-{random hex}`.
+pub fn request_id(prefix: &str) -> String {
+    format!("{prefix}-{:032x}", fastrand::u128(..))
+}
+
+pub async fn try_request_info(sender: Sender<ThreadRequest>, model: &str) -> Result<RuntimeInfo> {
     let (info_sender, info_receiver) = flume::unbounded();
-    let _ = sender.send(ThreadRequest::Info(info_sender));
+    let _ = sender.send(ThreadRequest::Info {
+        model: model.into(),
+        sender: info_sender,
+    });
     let info = info_receiver.recv_async().await?;
     Ok(info)
 }
 
-pub async fn request_info(sender: Sender<ThreadRequest>, sleep: Duration) -> RuntimeInfo {
-    loop {
-        if let Ok(info) = try_request_info(sender.clone()).await {
-            break info;
-        }
-        tokio::time::sleep(sleep).await;
+/// Demand the model for inference, wait until it is loaded, and return its runtime info.
+/// Fails if the model is not loaded after [`MODEL_WAIT_TIMEOUT`].
+pub async fn request_info(
+    sender: Sender<ThreadRequest>,
+    model: &str,
+) -> Result<RuntimeInfo, ApiError> {
+    let (demand_sender, demand_receiver) = flume::bounded(1);
+    let _ = sender.send(ThreadRequest::Demand {
+        model: model.into(),
+        sender: demand_sender,
+    });
+    let mut progress = demand_receiver
+        .recv_async()
+        .await
+        .map_err(|_| ApiError::ModelNotLoaded)??;
+
+    // loads always end in either phase, or the progress is dropped if the worker is gone
+    let wait =
+        progress.wait_for(|progress| matches!(progress.phase, LoadPhase::Done | LoadPhase::Failed));
+    let loaded = tokio::time::timeout(MODEL_WAIT_TIMEOUT, wait)
+        .await
+        .is_ok_and(|wait| wait.is_ok_and(|progress| progress.phase == LoadPhase::Done));
+    if !loaded {
+        return Err(ApiError::ModelNotLoaded);
     }
+    try_request_info(sender, model)
+        .await
+        .map_err(|_| ApiError::ModelNotLoaded)
 }
 
 pub async fn request_info_stream(
     sender: Sender<ThreadRequest>,
+    model: String,
     info_sender: Sender<RuntimeInfo>,
     sleep: Duration,
 ) {
-    loop {
-        if let Ok(info) = try_request_info(sender.clone()).await {
+    // stop once the client is gone, whether the model is loaded or not
+    while !info_sender.is_disconnected() {
+        if let Ok(info) = try_request_info(sender.clone(), &model).await {
             if info_sender.send(info).is_err() {
                 break;
             }
@@ -35,3 +75,21 @@ pub async fn request_info_stream(
         tokio::time::sleep(sleep).await;
     }
 }
+
+/// Wait until the generate request is admitted to the queue, and return the stream of its tokens.
+pub async fn admit(
+    receiver: Receiver<Token>,
+) -> Result<impl Stream<Item = Token> + Unpin, ApiError> {
+    let first = match receiver.recv_async().await {
+        Ok(Token::Reject(err)) => return Err(err.into()),
+        Ok(token) => token,
+        Err(_) => return Err(ApiError::Internal("failed to start the generation".into())),
+    };
+    Ok(stream::iter(Some(first)).chain(receiver.into_stream()))
+}
+
+/// Event telling a streaming client its position in the queue.
+pub fn queue_event(position: usize) -> Event {
+    let data = json!({ "position": position });
+    Event::default().event("queue").data(data.to_string())
+}